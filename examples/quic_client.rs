@@ -1,6 +1,8 @@
 use tokio::io::AsyncReadExt;
 
-fn main() -> bevy_technical_demo::Result<()> {
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+fn main() -> Result<()> {
     let config = bevy_technical_demo::config::load(&[])?;
 
     let runtime = tokio::runtime::Runtime::new()?;
@@ -10,12 +12,29 @@ fn main() -> bevy_technical_demo::Result<()> {
     Ok(())
 }
 
-async fn run(config: bevy_technical_demo::config::Config) -> bevy_technical_demo::Result<()> {
+async fn run(config: bevy_technical_demo::config::Config) -> Result<()> {
     let endpoint = create_endpoint(&config).await?;
 
     let addr = format!("{}:{}", config.quic_server.host, config.quic_server.port).parse()?;
     let connection = endpoint.connect(addr, &config.quic_server.name)?.await?;
 
+    let (mut send, recv) = connection.connection.open_bi().await?;
+
+    let hello = bevy_technical_demo::protocol::Handshake::Hello {
+        versions: bevy_technical_demo::protocol::Version::SUPPORTED.to_vec(),
+        resume_token: None,
+    };
+    let hello = hello.serialize()?;
+
+    send.write_all(&hello).await?;
+    send.finish().await?;
+
+    let response = recv.read_to_end(64 * 1024).await?;
+
+    let response = bevy_technical_demo::protocol::Handshake::deserialize(&response)?;
+
+    println!("handshake: {:?}", response);
+
     loop {
         let (mut send, recv) = connection.connection.open_bi().await?;
 
@@ -39,31 +58,33 @@ async fn run(config: bevy_technical_demo::config::Config) -> bevy_technical_demo
     // Ok(())
 }
 
-async fn create_endpoint(
-    c: &bevy_technical_demo::config::Config,
-) -> bevy_technical_demo::Result<quinn::Endpoint> {
-    // load server certificate
-    let mut certificate_file = tokio::fs::File::open(&c.quic_client.certificate).await?;
+async fn create_endpoint(c: &bevy_technical_demo::config::Config) -> Result<quinn::Endpoint> {
+    // create root certificate store
+    let mut roots = rustls::RootCertStore::empty();
 
-    let mut certificate_contents = vec![];
-    certificate_file
-        .read_to_end(&mut certificate_contents)
-        .await?;
+    for certificate_authority in c
+        .quic_client
+        .certificate_authorities
+        .iter()
+        .chain(&c.quic_client.certificate)
+    {
+        let mut certificate_file = tokio::fs::File::open(certificate_authority).await?;
 
-    let certificate = match rustls_pemfile::read_one(&mut &*certificate_contents)?.unwrap() {
-        rustls_pemfile::Item::X509Certificate(e) => rustls::Certificate(e),
-        _ => todo!(),
-    };
+        let mut certificate_contents = vec![];
+        certificate_file
+            .read_to_end(&mut certificate_contents)
+            .await?;
 
-    // create root certificate store
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(&certificate)?;
+        roots.add_parsable_certificates(&rustls_pemfile::certs(&mut &*certificate_contents)?);
+    }
 
     // create config
-    let crypto = rustls::ClientConfig::builder()
+    let mut crypto = rustls::ClientConfig::builder()
         .with_safe_defaults()
         .with_root_certificates(roots)
         .with_no_client_auth();
+    // payloads below are always JSON encoded
+    crypto.alpn_protocols = vec![bevy_technical_demo::codec::Format::Json.alpn().to_vec()];
 
     let config = quinn::ClientConfig::new(std::sync::Arc::new(crypto));
 
@@ -1,7 +1,9 @@
 use futures::StreamExt;
 use tokio::io::AsyncReadExt;
 
-fn main() -> bevy_technical_demo::Result<()> {
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+fn main() -> Result<()> {
     let config = bevy_technical_demo::config::load(&[])?;
 
     let runtime = tokio::runtime::Runtime::new()?;
@@ -11,7 +13,7 @@ fn main() -> bevy_technical_demo::Result<()> {
     Ok(())
 }
 
-async fn run(config: bevy_technical_demo::config::Config) -> bevy_technical_demo::Result<()> {
+async fn run(config: bevy_technical_demo::config::Config) -> Result<()> {
     let (endpoint, mut incoming) = create_endpoint(&config).await?;
 
     println!("listening on {}", endpoint.local_addr()?);
@@ -29,7 +31,7 @@ async fn run(config: bevy_technical_demo::config::Config) -> bevy_technical_demo
     Ok(())
 }
 
-async fn handle_connection(connection: quinn::Connecting) -> bevy_technical_demo::Result<()> {
+async fn handle_connection(connection: quinn::Connecting) -> Result<()> {
     let mut connection = connection.await?;
 
     println!(
@@ -48,6 +50,10 @@ async fn handle_connection(connection: quinn::Connecting) -> bevy_technical_demo
             )
     );
 
+    if let Some(stream) = connection.bi_streams.next().await {
+        handle_handshake(stream?).await?;
+    }
+
     while let Some(stream) = connection.bi_streams.next().await {
         let stream = match stream {
             Ok(s) => s,
@@ -68,9 +74,25 @@ async fn handle_connection(connection: quinn::Connecting) -> bevy_technical_demo
     Ok(())
 }
 
-async fn handle_request(
-    (mut send, recv): (quinn::SendStream, quinn::RecvStream),
-) -> bevy_technical_demo::Result<()> {
+async fn handle_handshake((mut send, recv): (quinn::SendStream, quinn::RecvStream)) -> Result<()> {
+    let request = recv.read_to_end(64 * 1024).await?;
+    let request = bevy_technical_demo::protocol::Handshake::deserialize(&request)?;
+
+    println!("handshake: {:?}", request);
+
+    let response = bevy_technical_demo::protocol::Handshake::Accept {
+        version: bevy_technical_demo::protocol::Version::V1,
+        resume_token: "00000000000000000000000000000000".into(),
+    };
+    let response = response.serialize()?;
+
+    send.write_all(&response).await?;
+    send.finish().await?;
+
+    Ok(())
+}
+
+async fn handle_request((mut send, recv): (quinn::SendStream, quinn::RecvStream)) -> Result<()> {
     let request = recv.read_to_end(64 * 1024).await?;
     let request = bevy_technical_demo::protocol::Payload::deserialize(&request)?;
 
@@ -88,10 +110,10 @@ async fn handle_request(
 
 async fn create_endpoint(
     c: &bevy_technical_demo::config::Config,
-) -> bevy_technical_demo::Result<(quinn::Endpoint, quinn::Incoming)> {
+) -> Result<(quinn::Endpoint, quinn::Incoming)> {
     // load server certificate
-    let mut certificate_file = tokio::fs::File::open(&c.quic_client.certificate).await?;
-    let mut private_key_file = tokio::fs::File::open(&c.quic_client.private_key).await?;
+    let mut certificate_file = tokio::fs::File::open(&c.quic_server.certificate).await?;
+    let mut private_key_file = tokio::fs::File::open(&c.quic_server.private_key).await?;
 
     let mut certificate_contents = vec![];
     certificate_file
@@ -0,0 +1,146 @@
+use bevy::prelude::*;
+
+use crate::network::{Connection, ConnectionStats, Detached};
+
+/// Requests from the admin HTTP API, answered by the Bevy schedule so the
+/// [`Connection`] entities stay the source of truth.
+#[derive(Debug)]
+pub(crate) enum Request {
+    ListConnections {
+        responder: tokio::sync::oneshot::Sender<Vec<ConnectionInfo>>,
+    },
+    GetConnection {
+        connection_id: usize,
+        responder: tokio::sync::oneshot::Sender<Option<ConnectionInfo>>,
+    },
+    CloseConnection {
+        connection_id: usize,
+        reason: String,
+        responder: tokio::sync::oneshot::Sender<bool>,
+    },
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub(crate) struct ConnectionInfo {
+    pub connection_id: usize,
+    pub remote_address: std::net::SocketAddr,
+    pub codec: crate::codec::Format,
+    pub version: crate::protocol::Version,
+    pub peer_subject: Option<String>,
+    pub rtt_ms: Option<f64>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub uptime_secs: u64,
+    pub detached: bool,
+}
+
+impl ConnectionInfo {
+    fn new(
+        connection: &Connection,
+        stats: &ConnectionStats,
+        detached: Option<&Detached>,
+    ) -> ConnectionInfo {
+        ConnectionInfo {
+            connection_id: connection.connection_id(),
+            remote_address: connection.remote_address(),
+            codec: connection.codec(),
+            version: connection.version(),
+            peer_subject: connection
+                .peer_identity()
+                .map(|peer_identity| peer_identity.subject.clone()),
+            rtt_ms: stats.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            uptime_secs: connection.uptime().as_secs(),
+            detached: detached.is_some(),
+        }
+    }
+}
+
+pub(crate) fn handle_requests(
+    mut receiver: ResMut<tokio::sync::mpsc::UnboundedReceiver<Request>>,
+    query: Query<(&Connection, &ConnectionStats, Option<&Detached>)>,
+    mut writer: EventWriter<crate::protocol::CloseConnectionEvent>,
+) {
+    let find = |connection_id| {
+        query
+            .iter()
+            .find(|(connection, _, _)| connection.connection_id() == connection_id)
+    };
+
+    // the HTTP handler may have timed out, so failing to respond is not an error.
+    while let Ok(request) = receiver.try_recv() {
+        match request {
+            Request::ListConnections { responder } => {
+                let _ = responder.send(
+                    query
+                        .iter()
+                        .map(|(connection, stats, detached)| {
+                            ConnectionInfo::new(connection, stats, detached)
+                        })
+                        .collect(),
+                );
+            }
+            Request::GetConnection {
+                connection_id,
+                responder,
+            } => {
+                let _ = responder.send(find(connection_id).map(|(connection, stats, detached)| {
+                    ConnectionInfo::new(connection, stats, detached)
+                }));
+            }
+            Request::CloseConnection {
+                connection_id,
+                reason,
+                responder,
+            } => {
+                let found = find(connection_id).is_some();
+                if found {
+                    info!(connection_id, reason, "admin closing connection");
+                    writer.send(crate::protocol::CloseConnectionEvent {
+                        connection_id,
+                        reason,
+                    });
+                }
+                let _ = responder.send(found);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_requests() {
+        let (admin_sender, admin_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut server = crate::loopback::Scripted::new(|app| {
+            app.insert_resource(admin_receiver)
+                .add_plugin(crate::network::Plugin::default())
+                .add_system(handle_requests);
+        });
+        let _outgoing = server.connect(1, "token", None);
+
+        let (responder, mut list) = tokio::sync::oneshot::channel();
+        admin_sender
+            .send(Request::ListConnections { responder })
+            .unwrap();
+        let (responder, mut missing) = tokio::sync::oneshot::channel();
+        admin_sender
+            .send(Request::CloseConnection {
+                connection_id: 2,
+                reason: "reason".into(),
+                responder,
+            })
+            .unwrap();
+        server.app.update();
+
+        let result = list.try_recv().unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].connection_id, 1);
+        assert!(!result[0].detached);
+        assert!(!missing.try_recv().unwrap());
+    }
+}
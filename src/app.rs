@@ -20,38 +20,83 @@ pub fn run() -> crate::Result<()> {
     // configure networking
     #[cfg(any(feature = "client", feature = "server"))]
     {
-        use crate::{config, network, quic};
+        use crate::{conditioner, config, network, quic};
 
         let config = config::load(&[])?;
         let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<crate::protocol::Event>();
+        let conditioner = conditioner::Conditioner::new(config.network.conditions);
 
         app.insert_resource(receiver);
-        app.add_plugin(network::Plugin);
+        app.insert_resource(conditioner.clone());
+        app.insert_resource(network::MultiplexSettings {
+            budget: config.network.budget,
+        });
+        app.insert_resource(network::ResumeSettings {
+            timeout: std::time::Duration::from_millis(config.network.resume_timeout),
+        });
+        app.add_plugin(network::Plugin::default());
 
         #[cfg(feature = "client")]
         {
             #[allow(clippy::redundant_clone)]
             let quic_config = config.clone();
 
+            #[allow(clippy::redundant_clone)]
+            let quic_conditioner = conditioner.clone();
+
             #[allow(clippy::redundant_clone)]
             let sender = sender.clone();
 
             runtime.spawn(async move {
-                if let Err(error) = quic::client::run(quic_config, sender).await {
-                    error!(error = error, "error");
+                if let Err(error) = quic::client::run(quic_config, quic_conditioner, sender).await {
+                    error!(error = %error, "error");
                 }
             });
         }
         #[cfg(feature = "server")]
         {
-            use crate::http_server;
+            use crate::{admin, health, http_server, shutdown};
+
+            let health = health::Health::default();
+            let (admin_sender, admin_receiver) =
+                tokio::sync::mpsc::unbounded_channel::<admin::Request>();
+            let (shutdown_sender, shutdown_receiver) =
+                tokio::sync::watch::channel(shutdown::Phase::Running);
+
+            app.insert_resource(health.clone())
+                .insert_resource(admin_receiver)
+                .insert_resource(shutdown_receiver.clone())
+                .add_system_to_stage(CoreStage::First, health::tick)
+                .add_system(admin::handle_requests)
+                .add_system(shutdown::exit);
+
+            let grace_period = std::time::Duration::from_millis(config.shutdown.grace_period);
+
+            #[allow(clippy::redundant_clone)]
+            let shutdown_health = health.clone();
+
+            runtime.spawn(async move {
+                if let Err(error) =
+                    shutdown::run(grace_period, shutdown_health, shutdown_sender).await
+                {
+                    error!(error = %error, "error");
+                }
+            });
 
             #[allow(clippy::redundant_clone)]
             let http_config = config.clone();
 
+            #[allow(clippy::redundant_clone)]
+            let http_health = health.clone();
+
+            #[allow(clippy::redundant_clone)]
+            let http_conditioner = conditioner.clone();
+
             runtime.spawn(async move {
-                if let Err(error) = http_server::run(http_config).await {
-                    error!(error = error, "error");
+                if let Err(error) =
+                    http_server::run(http_config, http_health, admin_sender, http_conditioner).await
+                {
+                    error!(error = %error, "error");
                 }
             });
 
@@ -62,8 +107,11 @@ pub fn run() -> crate::Result<()> {
             let sender = sender.clone();
 
             runtime.spawn(async move {
-                if let Err(error) = quic::server::run(quic_config, sender).await {
-                    error!(error = error, "error");
+                if let Err(error) =
+                    quic::server::run(quic_config, health, shutdown_receiver, conditioner, sender)
+                        .await
+                {
+                    error!(error = %error, "error");
                 }
             });
         }
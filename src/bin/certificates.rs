@@ -0,0 +1,380 @@
+//! Issues the certificates used by the QUIC client and server.
+//!
+//! Every command writes `<out>.crt` and `<out>.key`, certificates issued by an authority are
+//! written followed by the authority certificate so the file holds the full chain. An existing
+//! `<out>.key` is never replaced.
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const USAGE: &str = "\
+usage:
+  certificates ca [--name NAME] [--days DAYS] [--out PATH]
+  certificates server [--ca PATH] [--san NAME]... [--days DAYS] [--out PATH]
+  certificates client --ca PATH --name NAME [--days DAYS] [--out PATH]
+  certificates fingerprint PATH...
+
+--ca PATH reads PATH.crt and PATH.key, a server without --ca is self-signed.";
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Ca {
+        name: String,
+        days: i64,
+        out: String,
+    },
+    Server {
+        ca: Option<String>,
+        subject_alt_names: Vec<String>,
+        days: i64,
+        out: String,
+    },
+    Client {
+        ca: String,
+        name: String,
+        days: i64,
+        out: String,
+    },
+    Fingerprint {
+        paths: Vec<String>,
+    },
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    let command = match parse(&args) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("{error}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(error) = run(command) {
+        eprintln!("error: {error}");
+        std::process::exit(1);
+    }
+}
+
+fn parse(args: &[String]) -> Result<Command> {
+    let (command, args) = args.split_first().ok_or("missing command")?;
+
+    let mut name = None;
+    let mut days = None;
+    let mut out = None;
+    let mut ca = None;
+    let mut subject_alt_names = Vec::new();
+    let mut paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("missing value for {arg}"))
+        };
+
+        match arg.as_str() {
+            "--name" => name = Some(value()?),
+            "--days" => days = Some(value()?.parse::<i64>()?),
+            "--out" => out = Some(value()?),
+            "--ca" => ca = Some(value()?),
+            "--san" => subject_alt_names.push(value()?),
+            arg if arg.starts_with("--") => return Err(format!("unknown option {arg}").into()),
+            arg => paths.push(arg.to_string()),
+        }
+    }
+
+    if command != "fingerprint" && !paths.is_empty() {
+        return Err(format!("unexpected argument {}", paths[0]).into());
+    }
+
+    match command.as_str() {
+        "ca" => Ok(Command::Ca {
+            name: name.unwrap_or_else(|| "bevy-technical-demo".into()),
+            days: days.unwrap_or(3650),
+            out: out.unwrap_or_else(|| "ca".into()),
+        }),
+        "server" => Ok(Command::Server {
+            ca,
+            subject_alt_names: if subject_alt_names.is_empty() {
+                vec!["localhost".into()]
+            } else {
+                subject_alt_names
+            },
+            days: days.unwrap_or(365),
+            out: out.unwrap_or_else(|| "tls".into()),
+        }),
+        "client" => Ok(Command::Client {
+            ca: ca.ok_or("client requires --ca")?,
+            name: name.ok_or("client requires --name")?,
+            days: days.unwrap_or(365),
+            out: out.unwrap_or_else(|| "client".into()),
+        }),
+        "fingerprint" if !paths.is_empty() => Ok(Command::Fingerprint { paths }),
+        "fingerprint" => Err("fingerprint requires a path".into()),
+        command => Err(format!("unknown command {command}").into()),
+    }
+}
+
+fn run(command: Command) -> Result<()> {
+    match command {
+        Command::Ca { name, days, out } => {
+            let ca = rcgen::Certificate::from_params(ca_params(&name, days))?;
+            write(&out, ca.serialize_pem()?, ca.serialize_private_key_pem())
+        }
+        Command::Server {
+            ca,
+            subject_alt_names,
+            days,
+            out,
+        } => {
+            let certificate = rcgen::Certificate::from_params(leaf_params(
+                &subject_alt_names[0],
+                &subject_alt_names,
+                rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+                days,
+            ))?;
+            match ca {
+                Some(ca) => issue(&certificate, &ca, &out),
+                None => write(
+                    &out,
+                    certificate.serialize_pem()?,
+                    certificate.serialize_private_key_pem(),
+                ),
+            }
+        }
+        Command::Client {
+            ca,
+            name,
+            days,
+            out,
+        } => {
+            let certificate = rcgen::Certificate::from_params(leaf_params(
+                &name,
+                &[],
+                rcgen::ExtendedKeyUsagePurpose::ClientAuth,
+                days,
+            ))?;
+            issue(&certificate, &ca, &out)
+        }
+        Command::Fingerprint { paths } => {
+            for path in paths {
+                for (sha256, spki_sha256) in fingerprints(&std::fs::read(&path)?)? {
+                    println!("{path}\n  sha256:      {sha256}\n  spki sha256: {spki_sha256}");
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+fn ca_params(name: &str, days: i64) -> rcgen::CertificateParams {
+    let mut params = rcgen::CertificateParams::default();
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, name);
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    params.key_usages = vec![
+        rcgen::KeyUsagePurpose::KeyCertSign,
+        rcgen::KeyUsagePurpose::CrlSign,
+        rcgen::KeyUsagePurpose::DigitalSignature,
+    ];
+    validity(&mut params, days);
+    params
+}
+
+fn leaf_params(
+    name: &str,
+    subject_alt_names: &[String],
+    purpose: rcgen::ExtendedKeyUsagePurpose,
+    days: i64,
+) -> rcgen::CertificateParams {
+    let mut params = rcgen::CertificateParams::default();
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, name);
+    params.subject_alt_names = subject_alt_names
+        .iter()
+        .map(|name| match name.parse() {
+            Ok(address) => rcgen::SanType::IpAddress(address),
+            Err(_) => rcgen::SanType::DnsName(name.clone()),
+        })
+        .collect();
+    params.key_usages = vec![rcgen::KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![purpose];
+    validity(&mut params, days);
+    params
+}
+
+fn validity(params: &mut rcgen::CertificateParams, days: i64) {
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::minutes(5);
+    params.not_after = now + time::Duration::days(days);
+}
+
+/// Signs the certificate with the authority at `ca` and writes it followed by the authority.
+fn issue(certificate: &rcgen::Certificate, ca: &str, out: &str) -> Result<()> {
+    let ca_certificate = std::fs::read_to_string(format!("{ca}.crt"))?;
+    let ca_key = rcgen::KeyPair::from_pem(&std::fs::read_to_string(format!("{ca}.key"))?)?;
+    let signer = rcgen::Certificate::from_params(rcgen::CertificateParams::from_ca_cert_pem(
+        &ca_certificate,
+        ca_key,
+    )?)?;
+
+    write(
+        out,
+        certificate.serialize_pem_with_signer(&signer)? + &ca_certificate,
+        certificate.serialize_private_key_pem(),
+    )
+}
+
+/// Writes the private key readable by the owner only, refusing to replace an existing key.
+fn write(out: &str, certificate: String, private_key: String) -> Result<()> {
+    let key = format!("{out}.key");
+
+    // the key goes first so an existing one stops the command before anything is written.
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = match options.open(&key) {
+        Ok(file) => file,
+        Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(format!("{key} already exists, remove it or choose another --out").into())
+        }
+        Err(error) => return Err(error.into()),
+    };
+    std::io::Write::write_all(&mut file, private_key.as_bytes())?;
+
+    std::fs::write(format!("{out}.crt"), certificate)?;
+    println!("wrote {out}.crt and {out}.key");
+    Ok(())
+}
+
+/// SHA-256 of every certificate in the PEM file and of its public key.
+fn fingerprints(contents: &[u8]) -> Result<Vec<(String, String)>> {
+    rustls_pemfile::certs(&mut &*contents)?
+        .iter()
+        .map(|der| {
+            let (_, certificate) = x509_parser::parse_x509_certificate(der)?;
+            Ok((
+                hex(ring::digest::digest(&ring::digest::SHA256, der).as_ref()),
+                hex(
+                    ring::digest::digest(&ring::digest::SHA256, certificate.public_key().raw)
+                        .as_ref(),
+                ),
+            ))
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &str) -> Vec<String> {
+        args.split_whitespace().map(Into::into).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            parse(&args(
+                "server --ca ca --san localhost --san 127.0.0.1 --days 30"
+            ))
+            .unwrap(),
+            Command::Server {
+                ca: Some("ca".into()),
+                subject_alt_names: vec!["localhost".into(), "127.0.0.1".into()],
+                days: 30,
+                out: "tls".into(),
+            }
+        );
+        assert_eq!(
+            parse(&args("fingerprint a.crt b.crt")).unwrap(),
+            Command::Fingerprint {
+                paths: vec!["a.crt".into(), "b.crt".into()]
+            }
+        );
+        assert!(parse(&args("client --ca ca")).is_err());
+        assert!(parse(&args("ca --days")).is_err());
+        assert!(parse(&args("ca extra")).is_err());
+    }
+
+    #[test]
+    fn test_issue() {
+        let ca = rcgen::Certificate::from_params(ca_params("ca", 1)).unwrap();
+        let ca_der = ca.serialize_der().unwrap();
+        let certificate = rcgen::Certificate::from_params(leaf_params(
+            "localhost",
+            &["localhost".into(), "127.0.0.1".into()],
+            rcgen::ExtendedKeyUsagePurpose::ServerAuth,
+            1,
+        ))
+        .unwrap();
+        let der = certificate.serialize_der_with_signer(&ca).unwrap();
+
+        let (_, ca_x509) = x509_parser::parse_x509_certificate(&ca_der).unwrap();
+        let (_, x509) = x509_parser::parse_x509_certificate(&der).unwrap();
+
+        assert_eq!(x509.issuer(), ca_x509.subject());
+        assert!(x509.verify_signature(Some(ca_x509.public_key())).is_ok());
+        assert_eq!(
+            x509.subject_alternative_name()
+                .unwrap()
+                .unwrap()
+                .value
+                .general_names
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_fingerprints() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let pem = certificate.serialize_pem().unwrap();
+
+        let result = fingerprints(pem.as_bytes()).unwrap();
+
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].0.len(), 32 * 3 - 1);
+        assert_ne!(result[0].0, result[0].1);
+    }
+
+    #[test]
+    fn test_write() {
+        let out = std::env::temp_dir().join(format!("certificates-{}", std::process::id()));
+        let out = out.to_str().unwrap();
+
+        write(out, "certificate".into(), "key".into()).unwrap();
+        let result = write(out, "other certificate".into(), "other key".into());
+
+        let key = std::fs::read_to_string(format!("{out}.key")).unwrap();
+        let certificate = std::fs::read_to_string(format!("{out}.crt")).unwrap();
+        #[cfg(unix)]
+        let mode = std::os::unix::fs::PermissionsExt::mode(
+            &std::fs::metadata(format!("{out}.key"))
+                .unwrap()
+                .permissions(),
+        );
+        std::fs::remove_file(format!("{out}.key")).unwrap();
+        std::fs::remove_file(format!("{out}.crt")).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(key, "key");
+        assert_eq!(certificate, "certificate");
+        #[cfg(unix)]
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::protocol::Payload;
+
+/// Converts payloads to and from their wire representation.
+pub trait Codec: std::fmt::Debug + Send + Sync {
+    /// Serializes a payload into bytes.
+    ///
+    /// # Errors
+    ///
+    /// If the payload cannot be encoded, an error is returned.
+    fn serialize(&self, payload: &Payload) -> crate::Result<Vec<u8>>;
+
+    /// Deserializes a payload from bytes.
+    ///
+    /// # Errors
+    ///
+    /// If the bytes are not a valid payload, an error is returned.
+    fn deserialize(&self, bytes: &[u8]) -> crate::Result<Payload>;
+}
+
+/// Human readable codec, useful for debugging.
+#[derive(Debug)]
+pub struct Json;
+
+impl Codec for Json {
+    fn serialize(&self, payload: &Payload) -> crate::Result<Vec<u8>> {
+        serde_json::to_vec(payload).map_err(crate::Error::encode)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> crate::Result<Payload> {
+        serde_json::from_slice(bytes).map_err(crate::Error::decode)
+    }
+}
+
+/// Compact binary codec.
+#[derive(Debug)]
+pub struct MessagePack;
+
+impl Codec for MessagePack {
+    fn serialize(&self, payload: &Payload) -> crate::Result<Vec<u8>> {
+        // struct fields are written as maps so the tagged enum representation round trips.
+        rmp_serde::to_vec_named(payload).map_err(crate::Error::encode)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> crate::Result<Payload> {
+        rmp_serde::from_slice(bytes).map_err(crate::Error::decode)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum Format {
+    #[serde(rename = "json")]
+    Json,
+
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    pub const ALL: [Format; 2] = [Format::Json, Format::MessagePack];
+
+    #[must_use]
+    pub fn codec(self) -> std::sync::Arc<dyn Codec> {
+        match self {
+            Format::Json => std::sync::Arc::new(Json),
+            Format::MessagePack => std::sync::Arc::new(MessagePack),
+        }
+    }
+
+    /// ALPN identifier of the format, so the TLS handshake selects the codec of a connection.
+    #[must_use]
+    pub fn alpn(self) -> &'static [u8] {
+        match self {
+            Format::Json => b"bevy-technical-demo/json",
+            Format::MessagePack => b"bevy-technical-demo/msgpack",
+        }
+    }
+
+    #[must_use]
+    pub fn from_alpn(alpn: &[u8]) -> Option<Format> {
+        Format::ALL.into_iter().find(|format| format.alpn() == alpn)
+    }
+
+    /// ALPN identifiers to advertise, `self` first so it is preferred.
+    #[must_use]
+    pub fn alpn_protocols(self) -> Vec<Vec<u8>> {
+        std::iter::once(self)
+            .chain(Format::ALL.into_iter().filter(|format| *format != self))
+            .map(|format| format.alpn().to_vec())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{RpcError, Version1};
+
+    fn round_trip(codec: &dyn Codec) {
+        for payload in [
+            Payload::V1(Version1::Ping),
+            Payload::V1(Version1::Pong),
+            Payload::V1(Version1::Error(RpcError::Failed("reason".into()))),
+        ] {
+            let bytes = codec.serialize(&payload).unwrap();
+            let result = codec.deserialize(&bytes).unwrap();
+
+            assert_eq!(result, payload);
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        round_trip(&Json);
+
+        let result = Json.serialize(&Payload::V1(Version1::Ping)).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&result),
+            "{\"version\":\"1\",\"payload\":{\"type\":\"ping\"}}"
+        );
+    }
+
+    #[test]
+    fn test_message_pack() {
+        round_trip(&MessagePack);
+
+        let json = Json.serialize(&Payload::V1(Version1::Ping)).unwrap();
+        let result = MessagePack.serialize(&Payload::V1(Version1::Ping)).unwrap();
+        assert!(result.len() < json.len());
+    }
+
+    #[test]
+    fn test_alpn() {
+        for format in Format::ALL {
+            assert_eq!(Format::from_alpn(format.alpn()), Some(format));
+        }
+        assert_eq!(Format::from_alpn(b"h3"), None);
+
+        assert_eq!(
+            Format::MessagePack.alpn_protocols(),
+            vec![
+                b"bevy-technical-demo/msgpack".to_vec(),
+                b"bevy-technical-demo/json".to_vec()
+            ]
+        );
+    }
+}
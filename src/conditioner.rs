@@ -0,0 +1,624 @@
+//! Simulates a bad network between the network plugin and a transport.
+//!
+//! Every connection routes the [`protocol::Outgoing`] instructions of its side through a
+//! [`Link`], so conditions degrade what a peer sends: enable them on both peers to degrade both
+//! directions. The [`Conditioner`] applies the same conditions to every connection unless one is
+//! given its own. Unreliable messages can be lost, duplicated and reordered by jitter, reliable
+//! ones are never lost but arrive a retransmission later, and ordered channels keep their order.
+
+use bevy::prelude::*;
+
+use crate::{codec, protocol};
+
+/// Approximate bytes QUIC and UDP add to every payload, counted against the bandwidth.
+const OVERHEAD: usize = 48;
+
+/// Shortest time a sender takes to retransmit a lost packet, like the minimum RTO of TCP, so
+/// reliable losses cost something even without latency.
+const MIN_RETRANSMISSION_DELAY: std::time::Duration = std::time::Duration::from_millis(200);
+
+/// Conditions of a simulated link, every field defaults to a perfect link.
+#[derive(Clone, Copy, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Conditions {
+    /// One way delay added to everything sent, in milliseconds.
+    pub latency: u64,
+    /// Upper bound of a random delay added on top of `latency`, in milliseconds.
+    pub jitter: u64,
+    /// Probability between 0 and 1 that a packet is lost.
+    pub loss: f64,
+    /// Probability between 0 and 1 that an unreliable message is delivered twice.
+    pub duplication: f64,
+    /// Bytes per second the link carries, unlimited when `None`.
+    pub bandwidth: Option<u64>,
+}
+
+impl Conditions {
+    /// Checks the probabilities lie between 0 and 1.
+    ///
+    /// # Errors
+    ///
+    /// Names the first field out of range.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("loss", self.loss), ("duplication", self.duplication)] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(format!("{name} must be between 0 and 1, got {value}"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Conditions of every connection, those set for a single connection replace the default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Settings {
+    default: Option<Conditions>,
+    connections: std::collections::HashMap<usize, Conditions>,
+}
+
+impl Settings {
+    /// Conditions of the connection, `None` for a perfect link.
+    #[must_use]
+    pub fn get(&self, connection_id: usize) -> Option<Conditions> {
+        self.connections
+            .get(&connection_id)
+            .copied()
+            .or(self.default)
+    }
+}
+
+/// Shared switch of the link conditions, changes apply immediately.
+#[derive(Clone)]
+pub struct Conditioner {
+    sender: std::sync::Arc<tokio::sync::watch::Sender<Settings>>,
+}
+
+impl Conditioner {
+    /// Applies `conditions` to every connection.
+    #[must_use]
+    pub fn new(conditions: Option<Conditions>) -> Conditioner {
+        let (sender, _) = tokio::sync::watch::channel(Settings {
+            default: conditions,
+            connections: std::collections::HashMap::new(),
+        });
+
+        Conditioner {
+            sender: std::sync::Arc::new(sender),
+        }
+    }
+
+    /// Conditions of connections without their own, `None` when they get a perfect link.
+    #[must_use]
+    pub fn conditions(&self) -> Option<Conditions> {
+        self.sender.borrow().default
+    }
+
+    /// Replaces the conditions of connections without their own, `None` gives them a perfect
+    /// link.
+    pub fn set(&self, conditions: Option<Conditions>) {
+        info!(conditions = ?conditions, "link conditions changed");
+
+        self.sender
+            .send_modify(|settings| settings.default = conditions);
+    }
+
+    /// Conditions the connection gets, its own or the default.
+    #[must_use]
+    pub fn connection_conditions(&self, connection_id: usize) -> Option<Conditions> {
+        self.sender.borrow().get(connection_id)
+    }
+
+    /// Gives the connection its own conditions, `None` returns it to the default.
+    pub fn set_connection_conditions(&self, connection_id: usize, conditions: Option<Conditions>) {
+        info!(
+            connection_id = connection_id,
+            conditions = ?conditions,
+            "link conditions of connection changed"
+        );
+
+        self.sender.send_modify(|settings| match conditions {
+            Some(conditions) => {
+                settings.connections.insert(connection_id, conditions);
+            }
+            None => {
+                settings.connections.remove(&connection_id);
+            }
+        });
+    }
+
+    /// Drops the conditions of a connection that ended.
+    pub fn forget(&self, connection_id: usize) {
+        self.sender
+            .send_if_modified(|settings| settings.connections.remove(&connection_id).is_some());
+    }
+
+    /// Receives every change of the settings, for transports that own their links.
+    #[must_use]
+    pub fn subscribe(&self) -> tokio::sync::watch::Receiver<Settings> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Conditioner {
+    fn default() -> Conditioner {
+        Conditioner::new(None)
+    }
+}
+
+/// How an instruction travels, which decides what the conditions may do to it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    Unreliable,
+    Reliable,
+    Ordered(u8),
+    /// Sent after everything already scheduled.
+    Close,
+}
+
+impl Delivery {
+    fn of(outgoing: &protocol::Outgoing) -> Delivery {
+        match outgoing {
+            protocol::Outgoing::Message {
+                reliability: protocol::Reliability::Unreliable,
+                ..
+            } => Delivery::Unreliable,
+            protocol::Outgoing::Message {
+                channel,
+                reliability: protocol::Reliability::ReliableOrdered,
+                ..
+            } => Delivery::Ordered(*channel),
+            protocol::Outgoing::Message { .. }
+            | protocol::Outgoing::Request { .. }
+            | protocol::Outgoing::Response { .. } => Delivery::Reliable,
+            protocol::Outgoing::Close { .. } => Delivery::Close,
+        }
+    }
+}
+
+/// Holds back the instructions sent on one connection until the conditions let them arrive.
+pub struct Link {
+    conditions: Option<Conditions>,
+    rng: fastrand::Rng,
+    /// When the bandwidth lets the next payload leave.
+    busy_until: Option<std::time::Instant>,
+    /// Latest arrival on each ordered channel, later messages wait for it.
+    ordered: std::collections::HashMap<u8, std::time::Instant>,
+    /// Latest arrival of anything, a close waits for it.
+    last: Option<std::time::Instant>,
+    queue: std::collections::BinaryHeap<std::cmp::Reverse<Scheduled>>,
+    next_sequence: u64,
+}
+
+struct Scheduled {
+    at: std::time::Instant,
+    /// Keeps instructions due at the same instant in send order.
+    sequence: u64,
+    outgoing: protocol::Outgoing,
+}
+
+impl Link {
+    #[must_use]
+    pub fn new(conditions: Option<Conditions>) -> Link {
+        Link::with_seed(conditions, fastrand::u64(..))
+    }
+
+    /// Creates a link whose losses, duplicates and jitter are reproducible.
+    #[must_use]
+    pub fn with_seed(conditions: Option<Conditions>, seed: u64) -> Link {
+        Link {
+            conditions,
+            rng: fastrand::Rng::with_seed(seed),
+            busy_until: None,
+            ordered: std::collections::HashMap::new(),
+            last: None,
+            queue: std::collections::BinaryHeap::new(),
+            next_sequence: 0,
+        }
+    }
+
+    /// Applies to instructions sent from now on, those already held back keep their schedule.
+    pub fn set_conditions(&mut self, conditions: Option<Conditions>) {
+        self.conditions = conditions;
+    }
+
+    /// Schedules an instruction sent at `now`, the codec measures it against the bandwidth.
+    pub fn push(
+        &mut self,
+        now: std::time::Instant,
+        outgoing: protocol::Outgoing,
+        codec: &dyn codec::Codec,
+    ) {
+        let delivery = Delivery::of(&outgoing);
+        let mut at = now;
+
+        if let Some(conditions) = self.conditions {
+            if let Some(bandwidth) = conditions.bandwidth {
+                let start = self
+                    .busy_until
+                    .map_or(now, |busy_until| busy_until.max(now));
+                let transmission = std::time::Duration::from_secs_f64(
+                    size(codec, &outgoing) as f64 / bandwidth.max(1) as f64,
+                );
+
+                at = start + transmission;
+                self.busy_until = Some(at);
+            }
+
+            at += self.delay(conditions);
+
+            if self.rng.f64() < conditions.loss {
+                if delivery == Delivery::Unreliable {
+                    debug!("message lost");
+                    return;
+                }
+
+                // the sender notices the loss once its retransmission timer fires.
+                at += retransmission_delay(conditions);
+            }
+
+            if delivery == Delivery::Unreliable && self.rng.f64() < conditions.duplication {
+                if let Some(duplicate) = duplicate(&outgoing) {
+                    let duplicate_at =
+                        at + std::time::Duration::from_millis(self.rng.u64(0..=conditions.jitter));
+                    self.schedule(duplicate_at, duplicate);
+                }
+            }
+        }
+
+        match delivery {
+            Delivery::Ordered(channel) => {
+                let previous = self.ordered.entry(channel).or_insert(at);
+                at = at.max(*previous);
+                *previous = at;
+            }
+            Delivery::Close => at = self.last.map_or(at, |last| last.max(at)),
+            Delivery::Unreliable | Delivery::Reliable => {}
+        }
+
+        self.schedule(at, outgoing);
+    }
+
+    /// Takes the next instruction that arrived by `now`.
+    pub fn pop(&mut self, now: std::time::Instant) -> Option<protocol::Outgoing> {
+        match self.queue.peek() {
+            Some(std::cmp::Reverse(scheduled)) if scheduled.at <= now => {
+                self.queue.pop().map(|std::cmp::Reverse(x)| x.outgoing)
+            }
+            _ => None,
+        }
+    }
+
+    /// When the next held back instruction arrives.
+    #[must_use]
+    pub fn next_due(&self) -> Option<std::time::Instant> {
+        self.queue.peek().map(|std::cmp::Reverse(x)| x.at)
+    }
+
+    fn delay(&mut self, conditions: Conditions) -> std::time::Duration {
+        std::time::Duration::from_millis(conditions.latency + self.rng.u64(0..=conditions.jitter))
+    }
+
+    fn schedule(&mut self, at: std::time::Instant, outgoing: protocol::Outgoing) {
+        self.last = Some(self.last.map_or(at, |last| last.max(at)));
+        self.queue.push(std::cmp::Reverse(Scheduled {
+            at,
+            sequence: self.next_sequence,
+            outgoing,
+        }));
+        self.next_sequence += 1;
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        (self.at, self.sequence) == (other.at, other.sequence)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Scheduled) -> std::cmp::Ordering {
+        (self.at, self.sequence).cmp(&(other.at, other.sequence))
+    }
+}
+
+/// A round trip with the worst jitter, and never less than [`MIN_RETRANSMISSION_DELAY`].
+fn retransmission_delay(conditions: Conditions) -> std::time::Duration {
+    std::time::Duration::from_millis(2 * (conditions.latency + conditions.jitter))
+        .max(MIN_RETRANSMISSION_DELAY)
+}
+
+fn size(codec: &dyn codec::Codec, outgoing: &protocol::Outgoing) -> usize {
+    let payload = match outgoing {
+        protocol::Outgoing::Message { payload, .. }
+        | protocol::Outgoing::Request { payload, .. }
+        | protocol::Outgoing::Response {
+            response: Ok(payload),
+            ..
+        } => payload,
+        protocol::Outgoing::Response { .. } | protocol::Outgoing::Close { .. } => {
+            return OVERHEAD;
+        }
+    };
+
+    OVERHEAD + codec.serialize(payload).map_or(0, |bytes| bytes.len())
+}
+
+fn duplicate(outgoing: &protocol::Outgoing) -> Option<protocol::Outgoing> {
+    match outgoing {
+        protocol::Outgoing::Message {
+            channel,
+            reliability,
+            payload,
+        } => Some(protocol::Outgoing::Message {
+            channel: *channel,
+            reliability: *reliability,
+            payload: payload.clone(),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(channel: u8, reliability: protocol::Reliability) -> protocol::Outgoing {
+        protocol::Outgoing::Message {
+            channel,
+            reliability,
+            payload: protocol::Payload::V1(protocol::Version1::Ping),
+        }
+    }
+
+    fn numbered(channel: u8, number: usize) -> protocol::Outgoing {
+        protocol::Outgoing::Message {
+            channel,
+            reliability: protocol::Reliability::ReliableOrdered,
+            payload: protocol::Payload::V1(protocol::Version1::Error(protocol::RpcError::Failed(
+                number.to_string(),
+            ))),
+        }
+    }
+
+    fn drain(link: &mut Link, now: std::time::Instant) -> Vec<u8> {
+        std::iter::from_fn(|| link.pop(now))
+            .map(|outgoing| match outgoing {
+                protocol::Outgoing::Message { channel, .. } => channel,
+                outgoing => panic!("unexpected {outgoing:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_latency() {
+        let codec = codec::Format::Json.codec();
+        let now = std::time::Instant::now();
+        let mut link = Link::with_seed(None, 0);
+
+        link.push(now, message(2, protocol::Reliability::Unreliable), &*codec);
+        assert_eq!(drain(&mut link, now), vec![2]);
+
+        link.set_conditions(Some(Conditions {
+            latency: 100,
+            ..Conditions::default()
+        }));
+        link.push(now, message(3, protocol::Reliability::Unreliable), &*codec);
+
+        assert_eq!(
+            link.next_due(),
+            Some(now + std::time::Duration::from_millis(100))
+        );
+        assert!(drain(&mut link, now + std::time::Duration::from_millis(99)).is_empty());
+        assert_eq!(
+            drain(&mut link, now + std::time::Duration::from_millis(100)),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn test_loss() {
+        let codec = codec::Format::Json.codec();
+        let now = std::time::Instant::now();
+        let mut link = Link::with_seed(
+            Some(Conditions {
+                latency: 10,
+                loss: 1.0,
+                ..Conditions::default()
+            }),
+            0,
+        );
+
+        link.push(now, message(2, protocol::Reliability::Unreliable), &*codec);
+        link.push(
+            now,
+            message(3, protocol::Reliability::ReliableUnordered),
+            &*codec,
+        );
+
+        // the reliable message takes a retransmission longer, the unreliable one never arrives.
+        assert!(drain(&mut link, now + std::time::Duration::from_millis(209)).is_empty());
+        assert_eq!(
+            drain(&mut link, now + std::time::Duration::from_millis(210)),
+            vec![3]
+        );
+        assert_eq!(link.next_due(), None);
+    }
+
+    #[test]
+    fn test_duplication() {
+        let codec = codec::Format::Json.codec();
+        let now = std::time::Instant::now();
+        let mut link = Link::with_seed(
+            Some(Conditions {
+                duplication: 1.0,
+                ..Conditions::default()
+            }),
+            0,
+        );
+
+        link.push(now, message(2, protocol::Reliability::Unreliable), &*codec);
+        link.push(
+            now,
+            message(3, protocol::Reliability::ReliableOrdered),
+            &*codec,
+        );
+
+        assert_eq!(drain(&mut link, now), vec![2, 2, 3]);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(Conditions {
+            loss: 1.0,
+            duplication: 0.0,
+            ..Conditions::default()
+        }
+        .validate()
+        .is_ok());
+        assert!(Conditions {
+            loss: 1.5,
+            ..Conditions::default()
+        }
+        .validate()
+        .is_err());
+        assert!(Conditions {
+            duplication: -0.1,
+            ..Conditions::default()
+        }
+        .validate()
+        .is_err());
+        assert!(Conditions {
+            loss: f64::NAN,
+            ..Conditions::default()
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn test_ordered() {
+        let codec = codec::Format::Json.codec();
+        let now = std::time::Instant::now();
+        let mut link = Link::with_seed(
+            Some(Conditions {
+                jitter: 1000,
+                ..Conditions::default()
+            }),
+            0,
+        );
+
+        for number in 0..20 {
+            link.push(now, numbered(2, number), &*codec);
+            link.push(now, numbered(3, number), &*codec);
+        }
+        link.push(
+            now,
+            protocol::Outgoing::Close {
+                code: protocol::CloseReason::Kicked,
+                reason: "done".into(),
+            },
+            &*codec,
+        );
+
+        let mut arrived = Vec::new();
+        let mut closed = false;
+        while let Some(outgoing) = link.pop(now + std::time::Duration::from_secs(1)) {
+            assert!(!closed, "close overtaken");
+            match outgoing {
+                protocol::Outgoing::Message {
+                    channel, payload, ..
+                } => {
+                    arrived.push((channel, payload));
+                }
+                protocol::Outgoing::Close { .. } => closed = true,
+                outgoing => panic!("unexpected {outgoing:?}"),
+            }
+        }
+
+        assert!(closed);
+        assert_eq!(arrived.len(), 40);
+        // jitter interleaves the channels, each of them still arrives in order.
+        assert_ne!(arrived[0].0, arrived[1].0);
+        for channel in [2, 3] {
+            let payloads: Vec<_> = arrived
+                .iter()
+                .filter(|(x, _)| *x == channel)
+                .map(|(_, payload)| payload.clone())
+                .collect();
+            let expected: Vec<_> = (0..20)
+                .map(|number| match numbered(channel, number) {
+                    protocol::Outgoing::Message { payload, .. } => payload,
+                    _ => unreachable!(),
+                })
+                .collect();
+            assert_eq!(payloads, expected);
+        }
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let codec = codec::Format::Json.codec();
+        let now = std::time::Instant::now();
+        let size = size(&*codec, &message(2, protocol::Reliability::Unreliable));
+        let mut link = Link::with_seed(
+            Some(Conditions {
+                bandwidth: Some(size as u64 * 10),
+                ..Conditions::default()
+            }),
+            0,
+        );
+
+        link.push(now, message(2, protocol::Reliability::Unreliable), &*codec);
+        link.push(now, message(3, protocol::Reliability::Unreliable), &*codec);
+
+        assert_eq!(
+            drain(&mut link, now + std::time::Duration::from_millis(100)),
+            vec![2]
+        );
+        assert_eq!(
+            drain(&mut link, now + std::time::Duration::from_millis(200)),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn test_conditioner() {
+        let conditioner = Conditioner::default();
+        let receiver = conditioner.subscribe();
+
+        conditioner.set(Some(Conditions {
+            latency: 50,
+            ..Conditions::default()
+        }));
+
+        assert_eq!(conditioner.conditions().map(|x| x.latency), Some(50));
+        assert!(receiver.has_changed().unwrap());
+
+        // a connection with its own conditions leaves the others on the default.
+        conditioner.set_connection_conditions(1, Some(Conditions::default()));
+        assert_eq!(
+            conditioner.connection_conditions(1),
+            Some(Conditions::default())
+        );
+        assert_eq!(
+            conditioner.connection_conditions(2).map(|x| x.latency),
+            Some(50)
+        );
+        assert_eq!(receiver.borrow().get(1), Some(Conditions::default()));
+
+        conditioner.forget(1);
+        assert_eq!(
+            conditioner.connection_conditions(1).map(|x| x.latency),
+            Some(50)
+        );
+    }
+}
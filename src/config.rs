@@ -1,31 +1,101 @@
 #[derive(Clone, serde::Deserialize)]
 pub struct Config {
     pub http_server: HttpServer,
+    pub network: Network,
     pub quic_client: QuicClient,
     pub quic_server: QuicServer,
+    pub shutdown: Shutdown,
 }
 
 #[derive(Clone, serde::Deserialize)]
 pub struct HttpServer {
+    /// Bearer token required by the admin API, which is disabled when `None`.
+    pub admin_token: Option<String>,
+    /// Lets the admin API change the link conditions at runtime, off by default.
+    pub conditions_api: bool,
     pub host: String,
     pub port: u16,
 }
 
+#[derive(Clone, serde::Deserialize)]
+pub struct Network {
+    pub budget: Option<usize>,
+    /// Simulated link conditions applied to every connection, a perfect link when `None`.
+    pub conditions: Option<crate::conditioner::Conditions>,
+    /// How long a disconnected peer can resume its connection, in milliseconds.
+    pub resume_timeout: u64,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct Backoff {
+    /// Delay before the first reconnection attempt, in milliseconds.
+    pub initial_delay: u64,
+    /// Upper bound of the delay between reconnection attempts, in milliseconds.
+    pub max_delay: u64,
+    /// Consecutive failures before giving up, retries forever when `None`.
+    pub max_attempts: Option<u32>,
+}
+
+/// How the client decides to trust the server certificate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+pub enum Verification {
+    /// The certificate chains to one of `certificate_authorities`.
+    #[serde(rename = "authority")]
+    Authority,
+
+    /// The certificate public key matches one of `pinned_fingerprints`.
+    #[serde(rename = "pinned")]
+    Pinned,
+
+    /// Any certificate is accepted, for local development only.
+    #[serde(rename = "insecure")]
+    Insecure,
+}
+
 #[derive(Clone, serde::Deserialize)]
 pub struct QuicClient {
+    pub backoff: Backoff,
+    /// Authorities trusted to issue the server certificate, every certificate in each file is
+    /// trusted.
+    pub certificate_authorities: Vec<String>,
+    /// Another authority trusted like `certificate_authorities`, kept for configurations written
+    /// when it was the only one.
+    pub certificate: Option<String>,
+    /// Hex encoded SHA-256 fingerprints of trusted server public keys (SPKI).
+    pub pinned_fingerprints: Vec<String>,
+    pub verification: Verification,
+    /// Preferred codec, the server may select another it supports through ALPN.
+    pub codec: crate::codec::Format,
     pub host: String,
     pub port: u16,
-    pub certificate: String,
-    pub private_key: String,
+    /// Certificate chain presented when the server authenticates clients, together with
+    /// `client_private_key`. No certificate is presented when both are `None`.
+    pub client_certificate: Option<String>,
+    pub client_private_key: Option<String>,
 }
 
 #[derive(Clone, serde::Deserialize)]
 pub struct QuicServer {
+    /// Authority that issues client certificates, clients are not authenticated when `None`.
+    pub certificate_authority: Option<String>,
+    /// Preferred codec, used whenever the client also supports it.
+    pub codec: crate::codec::Format,
     pub host: String,
     pub port: u16,
     pub certificate: String,
     pub private_key: String,
     pub name: String,
+    /// How often the certificate files are checked for changes, in milliseconds. The files are
+    /// only read again on SIGHUP when 0.
+    pub reload_interval: u64,
+}
+
+#[derive(Clone, serde::Deserialize)]
+pub struct Shutdown {
+    /// How long the server drains before exiting, in milliseconds.
+    pub grace_period: u64,
+    /// Address draining clients are told to reconnect to.
+    pub redirect: Option<String>,
 }
 
 /// Loads the configuration from the environment variables and the config file.
@@ -37,24 +107,45 @@ pub fn load(overrides: &[(&str, &str)]) -> crate::Result<Config> {
     let mut config_builder = config::Config::builder()
         .set_default("http_server.host", "127.0.0.1")?
         .set_default("http_server.port", "80")?
+        .set_default("http_server.conditions_api", false)?
+        .set_default("network.resume_timeout", "30000")?
+        .set_default("quic_client.backoff.initial_delay", "100")?
+        .set_default("quic_client.backoff.max_delay", "10000")?
+        .set_default("quic_client.certificate_authorities", vec!["tls.crt"])?
+        .set_default("quic_client.pinned_fingerprints", Vec::<String>::new())?
+        .set_default("quic_client.verification", "authority")?
+        .set_default("quic_client.codec", "json")?
         .set_default("quic_client.host", "127.0.0.1")?
         .set_default("quic_client.port", "0")?
-        .set_default("quic_client.certificate", "tls.crt")?
-        .set_default("quic_client.private_key", "tls.key")?
+        .set_default("quic_server.codec", "json")?
         .set_default("quic_server.host", "127.0.0.1")?
         .set_default("quic_server.port", "4433")?
         .set_default("quic_server.certificate", "tls.crt")?
         .set_default("quic_server.private_key", "tls.key")?
         .set_default("quic_server.name", "localhost")?
+        .set_default("quic_server.reload_interval", "5000")?
+        .set_default("shutdown.grace_period", "10000")?
         .add_source(config::File::with_name("config").required(false))
-        .add_source(config::Environment::with_prefix("BEVY_TECHNICAL_DEMO").separator("__"));
+        .add_source(
+            config::Environment::with_prefix("BEVY_TECHNICAL_DEMO")
+                .separator("__")
+                .try_parsing(true)
+                .list_separator(",")
+                .with_list_parse_key("quic_client.certificate_authorities")
+                .with_list_parse_key("quic_client.pinned_fingerprints"),
+        );
 
     for &(key, value) in overrides {
         config_builder = config_builder.set_override(key, value)?;
     }
 
-    config_builder
-        .build()?
-        .try_deserialize()
-        .map_err(Into::into)
+    let config: Config = config_builder.build()?.try_deserialize()?;
+
+    if let Some(conditions) = &config.network.conditions {
+        conditions.validate().map_err(|error| {
+            config::ConfigError::Message(format!("network.conditions: {error}"))
+        })?;
+    }
+
+    Ok(config)
 }
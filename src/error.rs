@@ -0,0 +1,156 @@
+type Source = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("invalid configuration: {0}")]
+    Config(#[from] config::ConfigError),
+
+    #[error("invalid address: {0}")]
+    Address(#[from] std::net::AddrParseError),
+
+    /// A certificate or private key could not be loaded, or a peer certificate could not be read.
+    #[error("invalid certificate {name}: {source}")]
+    Certificate { name: String, source: Source },
+
+    #[error("tls: {0}")]
+    Tls(#[from] rustls::Error),
+
+    #[error("transport: {0}")]
+    Transport(#[from] TransportError),
+
+    #[error("failed to encode payload: {0}")]
+    Encode(#[source] Source),
+
+    #[error("failed to decode payload: {0}")]
+    Decode(#[source] Source),
+
+    /// The peer sent something the protocol does not allow.
+    #[error("protocol violation: {0}")]
+    Protocol(String),
+
+    #[error("incompatible version, peer supports {0:?}")]
+    IncompatibleVersion(Vec<crate::protocol::Version>),
+
+    #[error("{0} timed out")]
+    Timeout(&'static str),
+
+    /// The system random number generator failed.
+    #[error("failed to generate {0}")]
+    Random(&'static str),
+
+    #[error("gave up connecting after {0} attempts")]
+    AttemptsExhausted(u32),
+
+    /// The server closed the session for good, through the admin API for instance.
+    #[error("kicked by the server: {0}")]
+    Kicked(String),
+
+    /// The other end of an internal channel was dropped, usually because the app is stopping.
+    #[error("channel closed")]
+    ChannelClosed,
+
+    #[error("io: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("http: {0}")]
+    Http(#[from] hyper::Error),
+
+    #[error("metrics: {0}")]
+    Metrics(#[from] prometheus::Error),
+}
+
+/// Failures of the QUIC connection or one of its streams.
+#[derive(Debug, thiserror::Error)]
+pub enum TransportError {
+    #[error(transparent)]
+    Connect(#[from] quinn::ConnectError),
+
+    #[error(transparent)]
+    Connection(#[from] quinn::ConnectionError),
+
+    #[error(transparent)]
+    Write(#[from] quinn::WriteError),
+
+    #[error(transparent)]
+    Read(#[from] quinn::ReadError),
+
+    #[error(transparent)]
+    ReadExact(#[from] quinn::ReadExactError),
+
+    #[error(transparent)]
+    ReadToEnd(#[from] quinn::ReadToEndError),
+
+    #[error(transparent)]
+    SendDatagram(#[from] quinn::SendDatagramError),
+}
+
+impl Error {
+    #[cfg(any(feature = "client", feature = "server"))]
+    pub(crate) fn certificate(name: impl Into<String>, source: impl Into<Source>) -> Error {
+        Error::Certificate {
+            name: name.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn encode(source: impl Into<Source>) -> Error {
+        Error::Encode(source.into())
+    }
+
+    pub(crate) fn decode(source: impl Into<Source>) -> Error {
+        Error::Decode(source.into())
+    }
+}
+
+macro_rules! impl_from_transport {
+    ($($error:ty),*) => {
+        $(
+            impl From<$error> for Error {
+                fn from(error: $error) -> Error {
+                    Error::Transport(error.into())
+                }
+            }
+        )*
+    };
+}
+
+impl_from_transport!(
+    quinn::ConnectError,
+    quinn::ConnectionError,
+    quinn::WriteError,
+    quinn::ReadError,
+    quinn::ReadExactError,
+    quinn::ReadToEndError,
+    quinn::SendDatagramError
+);
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for Error {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Error {
+        Error::ChannelClosed
+    }
+}
+
+impl<T> From<tokio::sync::watch::error::SendError<T>> for Error {
+    fn from(_: tokio::sync::watch::error::SendError<T>) -> Error {
+        Error::ChannelClosed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel::<()>();
+        drop(receiver);
+        let error: Error = sender.send(()).unwrap_err().into();
+        assert!(matches!(error, Error::ChannelClosed));
+
+        let error: Error = quinn::ConnectionError::TimedOut.into();
+        assert!(matches!(
+            error,
+            Error::Transport(TransportError::Connection(quinn::ConnectionError::TimedOut))
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use bevy::prelude::*;
+
+/// How long the schedule may go without ticking before the instance is not ready.
+const TICK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Server state shared between the Bevy schedule, the QUIC listener and the HTTP server.
+#[derive(Clone, Default)]
+pub(crate) struct Health(std::sync::Arc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+    listening: AtomicBool,
+    /// Milliseconds since the unix epoch, zero until the first tick.
+    last_tick: AtomicU64,
+    draining: AtomicBool,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct Report {
+    pub status: Status,
+    pub checks: Checks,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct Checks {
+    pub quic_listener: Check,
+    pub schedule: ScheduleCheck,
+    pub draining: Check,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct Check {
+    pub status: Status,
+}
+
+#[derive(Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) struct ScheduleCheck {
+    pub status: Status,
+    /// Milliseconds since the unix epoch.
+    pub last_tick: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub(crate) enum Status {
+    #[serde(rename = "pass")]
+    Pass,
+
+    #[serde(rename = "fail")]
+    Fail,
+}
+
+impl Status {
+    fn from_pass(pass: bool) -> Status {
+        if pass {
+            Status::Pass
+        } else {
+            Status::Fail
+        }
+    }
+}
+
+impl Health {
+    pub(crate) fn set_listening(&self, listening: bool) {
+        self.0.listening.store(listening, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_draining(&self, draining: bool) {
+        self.0.draining.store(draining, Ordering::Relaxed);
+    }
+
+    fn tick(&self, now: std::time::SystemTime) {
+        self.0.last_tick.store(unix_millis(now), Ordering::Relaxed);
+    }
+
+    pub(crate) fn report(&self, now: std::time::SystemTime) -> Report {
+        let listening = self.0.listening.load(Ordering::Relaxed);
+        let draining = self.0.draining.load(Ordering::Relaxed);
+        let last_tick = match self.0.last_tick.load(Ordering::Relaxed) {
+            0 => None,
+            last_tick => Some(last_tick),
+        };
+
+        let ticking = matches!(
+            last_tick,
+            Some(last_tick) if unix_millis(now).saturating_sub(last_tick) <= TICK_TIMEOUT.as_millis() as u64
+        );
+
+        let checks = Checks {
+            quic_listener: Check {
+                status: Status::from_pass(listening),
+            },
+            schedule: ScheduleCheck {
+                status: Status::from_pass(ticking),
+                last_tick,
+            },
+            draining: Check {
+                status: Status::from_pass(!draining),
+            },
+        };
+
+        Report {
+            status: Status::from_pass(listening && ticking && !draining),
+            checks,
+        }
+    }
+}
+
+fn unix_millis(time: std::time::SystemTime) -> u64 {
+    time.duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+pub(crate) fn tick(health: Res<Health>) {
+    health.tick(std::time::SystemTime::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let health = Health::default();
+        let now = std::time::UNIX_EPOCH + std::time::Duration::from_secs(60);
+
+        assert_eq!(health.report(now).status, Status::Fail);
+
+        health.set_listening(true);
+        health.tick(now);
+
+        let report = health.report(now);
+        assert_eq!(report.status, Status::Pass);
+        assert_eq!(report.checks.schedule.last_tick, Some(60_000));
+
+        let report = health.report(now + TICK_TIMEOUT * 2);
+        assert_eq!(report.status, Status::Fail);
+        assert_eq!(report.checks.schedule.status, Status::Fail);
+
+        health.set_draining(true);
+
+        let report = health.report(now);
+        assert_eq!(report.status, Status::Fail);
+        assert_eq!(report.checks.draining.status, Status::Fail);
+        assert_eq!(report.checks.quic_listener.status, Status::Pass);
+    }
+
+    #[test]
+    fn test_report_json() {
+        let health = Health::default();
+
+        assert_eq!(
+            serde_json::to_string(&health.report(std::time::UNIX_EPOCH)).unwrap(),
+            "{\"status\":\"fail\",\"checks\":{\"quic_listener\":{\"status\":\"fail\"},\"schedule\":{\"status\":\"fail\",\"last_tick\":null},\"draining\":{\"status\":\"pass\"}}}"
+        );
+    }
+}
@@ -1,16 +1,76 @@
-use axum::{routing::get, Router, Server};
+use axum::{
+    extract::{FromRef, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    routing::{get, put},
+    Json, Router, Server,
+};
 use tokio::net::TcpListener;
 
-use crate::config;
+use crate::{admin, conditioner, config, health};
 
-pub(crate) async fn run(config: config::Config) -> crate::Result<()> {
+/// How long an admin request waits for the Bevy schedule to answer.
+const ADMIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+#[derive(Clone)]
+struct AppState {
+    admin_token: Option<String>,
+    admin: tokio::sync::mpsc::UnboundedSender<admin::Request>,
+    conditioner: conditioner::Conditioner,
+    health: health::Health,
+}
+
+impl FromRef<AppState> for health::Health {
+    fn from_ref(state: &AppState) -> health::Health {
+        state.health.clone()
+    }
+}
+
+pub(crate) async fn run(
+    config: config::Config,
+    health: health::Health,
+    admin: tokio::sync::mpsc::UnboundedSender<admin::Request>,
+    conditioner: conditioner::Conditioner,
+) -> crate::Result<()> {
     let addr = format!("{}:{}", config.http_server.host, config.http_server.port);
 
     let tcp_listener = TcpListener::bind(addr).await?.into_std()?;
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/health/liveness", get(|| async { "Ok" }))
-        .route("/health/readiness", get(|| async { "Ok" }));
+        .route("/health/readiness", get(readiness))
+        .route("/metrics", get(metrics));
+
+    if config.http_server.admin_token.is_some() {
+        app = app
+            .route("/admin/connections", get(list_connections))
+            .route(
+                "/admin/connections/:connection_id",
+                get(get_connection).delete(close_connection),
+            );
+
+        if config.http_server.conditions_api {
+            app = app
+                .route(
+                    "/admin/conditions",
+                    put(set_conditions)
+                        .get(get_conditions)
+                        .delete(clear_conditions),
+                )
+                .route(
+                    "/admin/connections/:connection_id/conditions",
+                    put(set_connection_conditions)
+                        .get(get_connection_conditions)
+                        .delete(clear_connection_conditions),
+                );
+        }
+    }
+
+    let app = app.with_state(AppState {
+        admin_token: config.http_server.admin_token,
+        admin,
+        conditioner,
+        health,
+    });
 
     let server = Server::from_tcp(tcp_listener)?.serve(app.into_make_service());
 
@@ -18,3 +78,209 @@ pub(crate) async fn run(config: config::Config) -> crate::Result<()> {
 
     Ok(())
 }
+
+async fn readiness(State(health): State<health::Health>) -> (StatusCode, Json<health::Report>) {
+    let report = health.report(std::time::SystemTime::now());
+
+    let status_code = match report.status {
+        health::Status::Pass => StatusCode::OK,
+        health::Status::Fail => StatusCode::SERVICE_UNAVAILABLE,
+    };
+
+    (status_code, Json(report))
+}
+
+async fn metrics() -> Result<String, (StatusCode, String)> {
+    crate::metrics::metrics()
+        .encode()
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))
+}
+
+#[derive(serde::Deserialize)]
+struct CloseConnection {
+    reason: Option<String>,
+}
+
+async fn list_connections(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<admin::ConnectionInfo>>, StatusCode> {
+    authorize(&state, &headers)?;
+
+    let connections = request(&state, |responder| admin::Request::ListConnections {
+        responder,
+    })
+    .await?;
+
+    Ok(Json(connections))
+}
+
+async fn get_connection(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(connection_id): Path<usize>,
+) -> Result<Json<admin::ConnectionInfo>, StatusCode> {
+    authorize(&state, &headers)?;
+
+    let connection = request(&state, |responder| admin::Request::GetConnection {
+        connection_id,
+        responder,
+    })
+    .await?;
+
+    connection.map(Json).ok_or(StatusCode::NOT_FOUND)
+}
+
+async fn close_connection(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(connection_id): Path<usize>,
+    Query(query): Query<CloseConnection>,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&state, &headers)?;
+
+    let found = request(&state, |responder| admin::Request::CloseConnection {
+        connection_id,
+        reason: query
+            .reason
+            .unwrap_or_else(|| "closed by administrator".into()),
+        responder,
+    })
+    .await?;
+
+    if found {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
+
+async fn get_conditions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<Option<conditioner::Conditions>>, StatusCode> {
+    authorize(&state, &headers)?;
+
+    Ok(Json(state.conditioner.conditions()))
+}
+
+async fn set_conditions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(conditions): Json<conditioner::Conditions>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    authorize(&state, &headers).map_err(|status| (status, String::new()))?;
+
+    conditions
+        .validate()
+        .map_err(|error| (StatusCode::UNPROCESSABLE_ENTITY, error))?;
+
+    state.conditioner.set(Some(conditions));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn clear_conditions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&state, &headers)?;
+
+    state.conditioner.set(None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn get_connection_conditions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(connection_id): Path<usize>,
+) -> Result<Json<Option<conditioner::Conditions>>, StatusCode> {
+    authorize(&state, &headers)?;
+    find_connection(&state, connection_id).await?;
+
+    Ok(Json(state.conditioner.connection_conditions(connection_id)))
+}
+
+async fn set_connection_conditions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(connection_id): Path<usize>,
+    Json(conditions): Json<conditioner::Conditions>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    authorize(&state, &headers).map_err(|status| (status, String::new()))?;
+
+    conditions
+        .validate()
+        .map_err(|error| (StatusCode::UNPROCESSABLE_ENTITY, error))?;
+
+    find_connection(&state, connection_id)
+        .await
+        .map_err(|status| (status, String::new()))?;
+
+    state
+        .conditioner
+        .set_connection_conditions(connection_id, Some(conditions));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn clear_connection_conditions(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(connection_id): Path<usize>,
+) -> Result<StatusCode, StatusCode> {
+    authorize(&state, &headers)?;
+    find_connection(&state, connection_id).await?;
+
+    state
+        .conditioner
+        .set_connection_conditions(connection_id, None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Conditions are only kept for connected clients, the transport drops them on disconnect.
+async fn find_connection(state: &AppState, connection_id: usize) -> Result<(), StatusCode> {
+    let connection = request(state, |responder| admin::Request::GetConnection {
+        connection_id,
+        responder,
+    })
+    .await?;
+
+    match connection {
+        Some(_) => Ok(()),
+        None => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let expected = state.admin_token.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    ring::constant_time::verify_slices_are_equal(token.as_bytes(), expected.as_bytes())
+        .map_err(|_| StatusCode::UNAUTHORIZED)
+}
+
+/// Forwards a request to the Bevy schedule and waits for its answer.
+async fn request<T>(
+    state: &AppState,
+    request: impl FnOnce(tokio::sync::oneshot::Sender<T>) -> admin::Request,
+) -> Result<T, StatusCode> {
+    let (responder, receiver) = tokio::sync::oneshot::channel();
+
+    state
+        .admin
+        .send(request(responder))
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+
+    match tokio::time::timeout(ADMIN_TIMEOUT, receiver).await {
+        Ok(Ok(response)) => Ok(response),
+        _ => Err(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
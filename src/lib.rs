@@ -1,11 +1,29 @@
+#[cfg(feature = "server")]
+mod admin;
 pub mod app;
+pub mod codec;
+pub mod conditioner;
 pub mod config;
+mod error;
+#[cfg(feature = "server")]
+mod health;
 #[cfg(feature = "server")]
 mod http_server;
-mod network;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod loopback;
+#[cfg(any(feature = "client", feature = "server"))]
+mod metrics;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod network;
 pub mod protocol;
 mod quic;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod replication;
+#[cfg(feature = "server")]
+mod shutdown;
+#[cfg(any(feature = "client", feature = "server"))]
+mod transport;
 
-pub type Error = Box<dyn std::error::Error + Send + Sync>;
+pub use error::{Error, TransportError};
 
 pub type Result<T> = std::result::Result<T, Error>;
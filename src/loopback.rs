@@ -0,0 +1,722 @@
+//! In-process transport connecting network plugins without sockets or certificates.
+//!
+//! Like the QUIC transport it only talks to [`crate::network::Plugin`] through
+//! [`protocol::Event`]s and [`protocol::Outgoing`] instructions, and shares its sessions with it,
+//! so the plugin cannot tell them apart. Nothing is delivered until [`Loopback::update`] runs,
+//! which keeps tests deterministic:
+//!
+//! ```ignore
+//! let (mut loopback, server_receiver) = Loopback::new(codec::Format::Json);
+//! let (client, client_receiver) = loopback.add_client();
+//! loopback.connect(client)?;
+//!
+//! server_app.update();
+//! client_app.update();
+//! loopback.update()?;
+//! ```
+//!
+//! Links can be degraded with [`Loopback::set_conditions`], time then only passes through
+//! [`Loopback::advance`] and losses are the same on every run.
+
+use crate::{
+    codec, conditioner, protocol,
+    transport::{self, Session},
+};
+
+/// First port of the addresses the server sees its clients connect from.
+const CLIENT_PORT: u16 = 49152;
+
+pub struct Loopback {
+    format: codec::Format,
+    codec: std::sync::Arc<dyn codec::Codec>,
+    server: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    clients: Vec<Client>,
+    next_connection_id: usize,
+    conditions: Option<conditioner::Conditions>,
+    /// Virtual clock of the conditioned links.
+    now: std::time::Instant,
+}
+
+struct Client {
+    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    resume_token: Option<String>,
+    link: Option<Link>,
+    /// Whether the client connected before, like the QUIC client it then reports reconnecting.
+    connected: bool,
+}
+
+/// A connection between a client and the server, seen under a different id by each side.
+struct Link {
+    client: End,
+    server: End,
+}
+
+struct End {
+    session: Session,
+    receiver: tokio::sync::mpsc::UnboundedReceiver<protocol::Outgoing>,
+    link: conditioner::Link,
+    /// Requests sent to the peer, by id, waiting for its network plugin to answer them.
+    requests: Vec<(u64, tokio::sync::oneshot::Receiver<transport::Response>)>,
+}
+
+/// Carries instructions of one [`End`] to the other.
+struct Pipe<'a> {
+    codec: &'a dyn codec::Codec,
+    from: &'a Session,
+    to: &'a Session,
+    requests: &'a mut Vec<(u64, tokio::sync::oneshot::Receiver<transport::Response>)>,
+    closed: Option<protocol::CloseReason>,
+}
+
+/// Which side of a [`Link`] sent an instruction.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Side {
+    Client,
+    Server,
+}
+
+impl Loopback {
+    /// Creates the transport and the receiver to insert into the server app.
+    #[must_use]
+    pub fn new(
+        format: codec::Format,
+    ) -> (
+        Loopback,
+        tokio::sync::mpsc::UnboundedReceiver<protocol::Event>,
+    ) {
+        let (server, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let loopback = Loopback {
+            format,
+            codec: format.codec(),
+            server,
+            clients: Vec::new(),
+            next_connection_id: 1,
+            conditions: None,
+            now: std::time::Instant::now(),
+        };
+
+        (loopback, receiver)
+    }
+
+    /// Adds a client and returns it with the receiver to insert into its app.
+    pub fn add_client(&mut self) -> (usize, tokio::sync::mpsc::UnboundedReceiver<protocol::Event>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        self.clients.push(Client {
+            sender,
+            resume_token: None,
+            link: None,
+            connected: false,
+        });
+
+        (self.clients.len() - 1, receiver)
+    }
+
+    /// Connects the client, resuming its previous session if it had one.
+    ///
+    /// # Errors
+    ///
+    /// If an app dropped its receiver, an error is returned.
+    ///
+    /// # Panics
+    ///
+    /// If the client was not added to this loopback.
+    pub fn connect(&mut self, client: usize) -> crate::Result<()> {
+        self.disconnect(client)?;
+
+        let client_connection_id = self.next_connection_id();
+        let server_connection_id = self.next_connection_id();
+        let port = CLIENT_PORT.wrapping_add(client as u16);
+
+        let client = &mut self.clients[client];
+        let resume_token = client
+            .resume_token
+            .get_or_insert_with(|| format!("{server_connection_id:032x}"))
+            .clone();
+
+        let (client_sender, client_receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (server_sender, server_receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        if !client.connected {
+            client.connected = true;
+            client.sender.send(protocol::Event::ConnectionStateChanged(
+                protocol::ConnectionState::Connecting,
+            ))?;
+        }
+        client.sender.send(protocol::Event::ConnectionStateChanged(
+            protocol::ConnectionState::Connected,
+        ))?;
+        client.sender.send(protocol::Event::ConnectionCreated(
+            protocol::ConnectionCreatedEvent {
+                connection_id: client_connection_id,
+                version: protocol::Version::V1,
+                resume_token: resume_token.clone(),
+                peer_identity: None,
+                remote_address: (std::net::Ipv4Addr::LOCALHOST, 4433).into(),
+                codec: self.format,
+                sender: client_sender,
+            },
+        ))?;
+
+        self.server.send(protocol::Event::ConnectionCreated(
+            protocol::ConnectionCreatedEvent {
+                connection_id: server_connection_id,
+                version: protocol::Version::V1,
+                resume_token,
+                peer_identity: None,
+                remote_address: (std::net::Ipv4Addr::LOCALHOST, port).into(),
+                codec: self.format,
+                sender: server_sender,
+            },
+        ))?;
+
+        client.link = Some(Link {
+            client: End::new(
+                Session::new(client_connection_id, client.sender.clone()),
+                client_receiver,
+                self.conditions,
+            ),
+            server: End::new(
+                Session::new(server_connection_id, self.server.clone()),
+                server_receiver,
+                self.conditions,
+            ),
+        });
+
+        Ok(())
+    }
+
+    /// Drops the client's connection as if the network failed, both sides may resume it.
+    ///
+    /// # Errors
+    ///
+    /// If an app dropped its receiver, an error is returned.
+    ///
+    /// # Panics
+    ///
+    /// If the client was not added to this loopback.
+    pub fn disconnect(&mut self, client: usize) -> crate::Result<()> {
+        let client = &mut self.clients[client];
+
+        match client.link.take() {
+            Some(link) => destroy(
+                &client.sender,
+                link,
+                protocol::ConnectionState::Reconnecting { attempt: 1 },
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Degrades every link in both directions, `None` restores perfect links.
+    pub fn set_conditions(&mut self, conditions: Option<conditioner::Conditions>) {
+        self.conditions = conditions;
+
+        for link in self.clients.iter_mut().filter_map(|x| x.link.as_mut()) {
+            link.client.link.set_conditions(conditions);
+            link.server.link.set_conditions(conditions);
+        }
+    }
+
+    /// Moves the clock of the conditioned links forward, then delivers what arrived meanwhile.
+    ///
+    /// # Errors
+    ///
+    /// If a payload cannot be encoded or an app dropped its receiver, an error is returned.
+    pub fn advance(&mut self, duration: std::time::Duration) -> crate::Result<()> {
+        self.now += duration;
+        self.update()
+    }
+
+    /// Delivers everything the plugins sent since the last update that arrived by now.
+    ///
+    /// # Errors
+    ///
+    /// If a payload cannot be encoded or an app dropped its receiver, an error is returned.
+    pub fn update(&mut self) -> crate::Result<()> {
+        for client in &mut self.clients {
+            let mut link = match client.link.take() {
+                Some(link) => link,
+                None => continue,
+            };
+
+            let mut closed = None;
+            for side in [Side::Client, Side::Server] {
+                let (from, to) = match side {
+                    Side::Client => (&mut link.client, &mut link.server),
+                    Side::Server => (&mut link.server, &mut link.client),
+                };
+
+                while let Ok(outgoing) = from.receiver.try_recv() {
+                    from.link.push(self.now, outgoing, &*self.codec);
+                }
+
+                let mut pipe = Pipe {
+                    codec: &*self.codec,
+                    from: &from.session,
+                    to: &to.session,
+                    requests: &mut from.requests,
+                    closed: None,
+                };
+                while let Some(outgoing) = from.link.pop(self.now) {
+                    from.session.dispatch(&mut pipe, outgoing)?;
+                    if pipe.closed.is_some() {
+                        break;
+                    }
+                }
+
+                if pipe.closed.is_some() {
+                    closed = pipe.closed;
+                    break;
+                }
+            }
+
+            // responses the plugins just handed over travel with the instructions that carried
+            // them.
+            for end in [&mut link.client, &mut link.server] {
+                end.answered(&*self.codec)?;
+            }
+
+            if let Some(code) = closed {
+                // a kicked client does not get to resume its session, and like the QUIC client
+                // gives up.
+                let state = if code == protocol::CloseReason::Kicked {
+                    client.resume_token = None;
+                    protocol::ConnectionState::Failed
+                } else {
+                    protocol::ConnectionState::Reconnecting { attempt: 1 }
+                };
+                destroy(&client.sender, link, state)?;
+            } else {
+                client.link = Some(link);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn next_connection_id(&mut self) -> usize {
+        let connection_id = self.next_connection_id;
+        self.next_connection_id += 1;
+        connection_id
+    }
+}
+
+impl End {
+    fn new(
+        session: Session,
+        receiver: tokio::sync::mpsc::UnboundedReceiver<protocol::Outgoing>,
+        conditions: Option<conditioner::Conditions>,
+    ) -> End {
+        let seed = session.connection_id() as u64;
+
+        End {
+            session,
+            receiver,
+            link: conditioner::Link::with_seed(conditions, seed),
+            requests: Vec::new(),
+        }
+    }
+
+    /// Hands the responses the peer answered to the plugin that sent the requests.
+    fn answered(&mut self, codec: &dyn codec::Codec) -> crate::Result<()> {
+        let mut requests = Vec::new();
+
+        for (request_id, mut receiver) in self.requests.drain(..) {
+            let response = match receiver.try_recv() {
+                Ok(Ok(payload)) => Ok(transfer(codec, &payload)?),
+                Ok(Err(error)) => Err(error),
+                Err(tokio::sync::oneshot::error::TryRecvError::Empty) => {
+                    requests.push((request_id, receiver));
+                    continue;
+                }
+                Err(tokio::sync::oneshot::error::TryRecvError::Closed) => {
+                    Err(protocol::RpcError::Closed)
+                }
+            };
+
+            self.session.response(request_id, response)?;
+        }
+
+        self.requests = requests;
+
+        Ok(())
+    }
+}
+
+impl transport::Pipe for Pipe<'_> {
+    fn carry(&mut self, outgoing: protocol::Outgoing) -> crate::Result<()> {
+        match outgoing {
+            protocol::Outgoing::Message {
+                channel, payload, ..
+            } => self.to.message(channel, transfer(self.codec, &payload)?),
+            // the timeout is not enforced, a request stays pending until answered or disconnected.
+            protocol::Outgoing::Request {
+                request_id,
+                payload,
+                ..
+            } => match self.to.request(transfer(self.codec, &payload)?)? {
+                transport::Answer::Now(response) => self.from.response(request_id, Ok(response)),
+                transport::Answer::Later { receiver, .. } => {
+                    self.requests.push((request_id, receiver));
+                    Ok(())
+                }
+            },
+            protocol::Outgoing::Response { request_id, .. } => {
+                Err(transport::unanswerable(request_id))
+            }
+            protocol::Outgoing::Close { code, .. } => {
+                self.closed = Some(code);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Round trips the payload through the codec, so it arrives exactly as it would over QUIC.
+fn transfer(
+    codec: &dyn codec::Codec,
+    payload: &protocol::Payload,
+) -> crate::Result<protocol::Payload> {
+    codec.deserialize(&codec.serialize(payload)?)
+}
+
+/// Fails the requests still waiting on the link and tells both sides it is gone, the client
+/// then reports `state` like the QUIC client would.
+fn destroy(
+    client: &tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    link: Link,
+    state: protocol::ConnectionState,
+) -> crate::Result<()> {
+    for end in [&link.client, &link.server] {
+        for &(request_id, _) in &end.requests {
+            end.session
+                .response(request_id, Err(protocol::RpcError::Closed))?;
+        }
+
+        end.session.destroyed()?;
+    }
+
+    client.send(protocol::Event::ConnectionStateChanged(state))?;
+
+    Ok(())
+}
+
+/// A server app and client apps connected through a [`Loopback`], for tests.
+#[cfg(test)]
+pub(crate) struct Fixture {
+    pub(crate) loopback: Loopback,
+    pub(crate) server: bevy::prelude::App,
+    /// Indexed like the clients of the loopback.
+    pub(crate) clients: Vec<bevy::prelude::App>,
+}
+
+#[cfg(test)]
+impl Fixture {
+    /// Creates the server app, `setup` adds the network plugin and whatever else the test needs.
+    pub(crate) fn new(
+        format: codec::Format,
+        setup: impl FnOnce(&mut bevy::prelude::App),
+    ) -> Fixture {
+        let (loopback, receiver) = Loopback::new(format);
+
+        Fixture {
+            loopback,
+            server: app(receiver, setup),
+            clients: Vec::new(),
+        }
+    }
+
+    /// Adds a client app set up like the server app and connects it.
+    pub(crate) fn add_client(&mut self, setup: impl FnOnce(&mut bevy::prelude::App)) -> usize {
+        let (client, receiver) = self.loopback.add_client();
+        self.clients.push(app(receiver, setup));
+        self.loopback.connect(client).unwrap();
+        client
+    }
+
+    /// Runs a frame of the server, delivers what arrived, then runs a frame of every client.
+    pub(crate) fn step(&mut self) {
+        self.advance(std::time::Duration::ZERO);
+    }
+
+    /// Like [`Fixture::step`], with the clock of the conditioned links moved forward first.
+    pub(crate) fn advance(&mut self, duration: std::time::Duration) {
+        self.server.update();
+        self.loopback.advance(duration).unwrap();
+        for client in &mut self.clients {
+            client.update();
+        }
+    }
+}
+
+/// An app fed transport events by hand, for tests of what the network plugin makes of them.
+#[cfg(test)]
+pub(crate) struct Scripted {
+    pub(crate) app: bevy::prelude::App,
+    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+}
+
+#[cfg(test)]
+impl Scripted {
+    /// Creates the app, `setup` adds the network plugin and whatever else the test needs.
+    pub(crate) fn new(setup: impl FnOnce(&mut bevy::prelude::App)) -> Scripted {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        Scripted {
+            app: app(receiver, setup),
+            sender,
+        }
+    }
+
+    /// Hands the event to the app and runs a frame.
+    pub(crate) fn send(&mut self, event: protocol::Event) {
+        self.sender.send(event).unwrap();
+        self.app.update();
+    }
+
+    /// Creates a connection like a transport would, returns what the app sends on it.
+    pub(crate) fn connect(
+        &mut self,
+        connection_id: usize,
+        resume_token: &str,
+        peer_identity: Option<protocol::PeerIdentity>,
+    ) -> tokio::sync::mpsc::UnboundedReceiver<protocol::Outgoing> {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        self.send(protocol::Event::ConnectionCreated(
+            protocol::ConnectionCreatedEvent {
+                connection_id,
+                version: protocol::Version::V1,
+                resume_token: resume_token.into(),
+                peer_identity,
+                remote_address: ([127, 0, 0, 1], CLIENT_PORT).into(),
+                codec: codec::Format::Json,
+                sender,
+            },
+        ));
+
+        receiver
+    }
+
+    /// Drops a connection like a transport would.
+    pub(crate) fn disconnect(&mut self, connection_id: usize) {
+        self.send(protocol::Event::ConnectionDestroyed(
+            protocol::ConnectionDestroyedEvent { connection_id },
+        ));
+    }
+}
+
+#[cfg(test)]
+fn app(
+    receiver: tokio::sync::mpsc::UnboundedReceiver<protocol::Event>,
+    setup: impl FnOnce(&mut bevy::prelude::App),
+) -> bevy::prelude::App {
+    let mut app = bevy::prelude::App::new();
+    app.insert_resource(receiver);
+    setup(&mut app);
+    app
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::{ecs::event::Events, prelude::*};
+
+    use super::*;
+    use crate::network::{Connection, Detached};
+
+    fn setup(app: &mut App) {
+        app.add_plugin(crate::network::Plugin::default());
+    }
+
+    fn connection_ids(app: &mut App) -> Vec<usize> {
+        app.world
+            .query_filtered::<&Connection, Without<Detached>>()
+            .iter(&app.world)
+            .map(Connection::connection_id)
+            .collect()
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut fixture = Fixture::new(codec::Format::MessagePack, setup);
+        fixture.add_client(setup);
+        fixture.step();
+
+        let client_connection_id = connection_ids(&mut fixture.clients[0])[0];
+        let server_connection_id = connection_ids(&mut fixture.server)[0];
+        assert_ne!(client_connection_id, server_connection_id);
+
+        let client = &mut fixture.clients[0];
+        client
+            .world
+            .resource_mut::<Events<protocol::SendPayloadEvent>>()
+            .send(protocol::SendPayloadEvent {
+                target: protocol::Target::Broadcast,
+                payload: protocol::Payload::V1(protocol::Version1::Pong),
+            });
+        client
+            .world
+            .resource_mut::<Events<protocol::SendRequestEvent>>()
+            .send(protocol::SendRequestEvent {
+                connection_id: client_connection_id,
+                request_id: 7,
+                payload: protocol::Payload::V1(protocol::Version1::Pong),
+                timeout: std::time::Duration::from_secs(1),
+            });
+
+        let mut payloads = fixture
+            .server
+            .world
+            .resource::<Events<protocol::PayloadReceivedEvent>>()
+            .get_reader();
+        let mut responses = fixture.clients[0]
+            .world
+            .resource::<Events<protocol::ResponseReceivedEvent>>()
+            .get_reader();
+        let mut received = Vec::new();
+        let mut answered = Vec::new();
+
+        // client sends, request leaves, server receives and answers, which may take it another
+        // frame.
+        for _ in 0..4 {
+            fixture.step();
+
+            let events = fixture
+                .server
+                .world
+                .resource::<Events<protocol::PayloadReceivedEvent>>();
+            received.extend(payloads.iter(events).map(|event| event.connection_id));
+            let events = fixture.clients[0]
+                .world
+                .resource::<Events<protocol::ResponseReceivedEvent>>();
+            answered.extend(
+                responses
+                    .iter(events)
+                    .map(|event| (event.request_id, event.response.clone())),
+            );
+        }
+
+        assert_eq!(received, [server_connection_id]);
+        assert_eq!(answered, [(7, Err(protocol::RpcError::Unhandled))]);
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut fixture = Fixture::new(codec::Format::Json, setup);
+        fixture
+            .loopback
+            .set_conditions(Some(conditioner::Conditions {
+                latency: 100,
+                ..conditioner::Conditions::default()
+            }));
+        fixture.add_client(setup);
+        fixture.step();
+
+        let client_connection_id = connection_ids(&mut fixture.clients[0])[0];
+        fixture.clients[0]
+            .world
+            .resource_mut::<Events<protocol::SendRequestEvent>>()
+            .send(protocol::SendRequestEvent {
+                connection_id: client_connection_id,
+                request_id: 7,
+                payload: protocol::Payload::V1(protocol::Version1::Pong),
+                timeout: std::time::Duration::from_secs(1),
+            });
+
+        let mut reader = fixture.clients[0]
+            .world
+            .resource::<Events<protocol::ResponseReceivedEvent>>()
+            .get_reader();
+        let mut responses = |fixture: &mut Fixture, duration| {
+            fixture.advance(std::time::Duration::from_millis(duration));
+            let events = fixture.clients[0]
+                .world
+                .resource::<Events<protocol::ResponseReceivedEvent>>();
+            reader.iter(events).count()
+        };
+
+        // the client sends in the first frame and the request leaves in the next, then the
+        // request and its answer each take 100 milliseconds. The server may take another frame
+        // to answer.
+        assert_eq!(responses(&mut fixture, 0), 0);
+        assert_eq!(responses(&mut fixture, 0), 0);
+        assert_eq!(responses(&mut fixture, 100), 0);
+        assert_eq!(responses(&mut fixture, 0), 0);
+        assert_eq!(responses(&mut fixture, 0), 0);
+        assert_eq!(responses(&mut fixture, 99), 0);
+        assert_eq!(responses(&mut fixture, 1), 1);
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut fixture = Fixture::new(codec::Format::Json, setup);
+        let client = fixture.add_client(setup);
+        fixture.step();
+
+        assert_eq!(
+            *fixture.clients[0]
+                .world
+                .resource::<protocol::ConnectionState>(),
+            protocol::ConnectionState::Connected
+        );
+
+        fixture.loopback.disconnect(client).unwrap();
+        fixture.step();
+        assert!(connection_ids(&mut fixture.server).is_empty());
+        assert_eq!(
+            *fixture.clients[0]
+                .world
+                .resource::<protocol::ConnectionState>(),
+            protocol::ConnectionState::Reconnecting { attempt: 1 }
+        );
+
+        fixture.loopback.connect(client).unwrap();
+        fixture.step();
+
+        assert_eq!(
+            fixture
+                .server
+                .world
+                .query::<&Connection>()
+                .iter(&fixture.server.world)
+                .count(),
+            1
+        );
+        assert_eq!(connection_ids(&mut fixture.server).len(), 1);
+    }
+
+    #[test]
+    fn test_kick() {
+        let mut fixture = Fixture::new(codec::Format::Json, setup);
+        let client = fixture.add_client(setup);
+        fixture.step();
+
+        let connection_id = connection_ids(&mut fixture.server)[0];
+        fixture
+            .server
+            .world
+            .resource_mut::<Events<protocol::CloseConnectionEvent>>()
+            .send(protocol::CloseConnectionEvent {
+                connection_id,
+                reason: "cheating".into(),
+            });
+        fixture.step();
+        fixture.step();
+
+        assert!(connection_ids(&mut fixture.server).is_empty());
+        assert_eq!(
+            *fixture.clients[0]
+                .world
+                .resource::<protocol::ConnectionState>(),
+            protocol::ConnectionState::Failed
+        );
+
+        // connecting again starts a new session.
+        fixture.loopback.connect(client).unwrap();
+        fixture.step();
+
+        assert_eq!(connection_ids(&mut fixture.server).len(), 1);
+    }
+}
@@ -0,0 +1,147 @@
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+};
+
+static METRICS: once_cell::sync::Lazy<Metrics> =
+    once_cell::sync::Lazy::new(|| Metrics::new().expect("invalid metric"));
+
+/// Process wide metrics, exposed by the HTTP server in the Prometheus text format.
+pub(crate) struct Metrics {
+    registry: prometheus::Registry,
+
+    pub(crate) quic_connections: IntGauge,
+    pub(crate) quic_streams_opened: IntCounterVec,
+    pub(crate) quic_stream_errors: IntCounter,
+    pub(crate) quic_payloads: IntCounterVec,
+    pub(crate) quic_decode_failures: IntCounter,
+    pub(crate) quic_bytes: IntCounterVec,
+    pub(crate) quic_rtt: Histogram,
+
+    pub(crate) network_events: Histogram,
+    pub(crate) network_connections: IntGaugeVec,
+    pub(crate) network_frame_time: Histogram,
+}
+
+pub(crate) fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Metrics> {
+        let registry = prometheus::Registry::new();
+
+        let metrics = Metrics {
+            quic_connections: IntGauge::new("quic_connections", "Open QUIC connections.")?,
+            quic_streams_opened: IntCounterVec::new(
+                Opts::new("quic_streams_opened_total", "QUIC streams opened."),
+                &["direction", "kind"],
+            )?,
+            quic_stream_errors: IntCounter::new(
+                "quic_stream_errors_total",
+                "QUIC streams that failed before completing.",
+            )?,
+            quic_payloads: IntCounterVec::new(
+                Opts::new(
+                    "quic_payloads_total",
+                    "Payloads sent or received by variant.",
+                ),
+                &["direction", "kind"],
+            )?,
+            quic_decode_failures: IntCounter::new(
+                "quic_decode_failures_total",
+                "Payloads that could not be decoded.",
+            )?,
+            quic_bytes: IntCounterVec::new(
+                Opts::new("quic_bytes_total", "UDP bytes sent or received."),
+                &["direction"],
+            )?,
+            quic_rtt: Histogram::with_opts(
+                HistogramOpts::new("quic_rtt_seconds", "Round trip time of QUIC connections.")
+                    .buckets(prometheus::exponential_buckets(0.001, 2.0, 12)?),
+            )?,
+            network_events: Histogram::with_opts(
+                HistogramOpts::new(
+                    "network_events_per_frame",
+                    "Transport events forwarded to the schedule per frame.",
+                )
+                .buckets(prometheus::exponential_buckets(1.0, 4.0, 8)?),
+            )?,
+            network_connections: IntGaugeVec::new(
+                Opts::new("network_connections", "Connection entities by state."),
+                &["state"],
+            )?,
+            network_frame_time: Histogram::with_opts(
+                HistogramOpts::new("network_frame_time_seconds", "Duration of a frame.")
+                    .buckets(prometheus::exponential_buckets(0.001, 2.0, 12)?),
+            )?,
+            registry,
+        };
+
+        metrics
+            .registry
+            .register(Box::new(metrics.quic_connections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.quic_streams_opened.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.quic_stream_errors.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.quic_payloads.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.quic_decode_failures.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.quic_bytes.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.quic_rtt.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.network_events.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.network_connections.clone()))?;
+        metrics
+            .registry
+            .register(Box::new(metrics.network_frame_time.clone()))?;
+
+        Ok(metrics)
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    #[cfg(any(test, feature = "server"))]
+    pub(crate) fn encode(&self) -> crate::Result<String> {
+        let mut buffer = Vec::new();
+        prometheus::Encoder::encode(
+            &prometheus::TextEncoder::new(),
+            &self.registry.gather(),
+            &mut buffer,
+        )?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let metrics = Metrics::new().unwrap();
+
+        metrics
+            .quic_payloads
+            .with_label_values(&["received", "ping"])
+            .inc();
+        metrics.quic_decode_failures.inc();
+
+        let result = metrics.encode().unwrap();
+
+        assert!(result.contains("quic_payloads_total{direction=\"received\",kind=\"ping\"} 1\n"));
+        assert!(result.contains("quic_decode_failures_total 1\n"));
+        assert!(result.contains("# TYPE network_frame_time_seconds histogram\n"));
+    }
+}
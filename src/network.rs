@@ -1,61 +1,553 @@
-use bevy::prelude::*;
+use bevy::{ecs::system::SystemParam, prelude::*};
 
-pub(crate) struct Plugin;
+/// How long a request sent through [`Rpc::request`] waits for a response.
+pub const DEFAULT_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+type AddChannel = fn(&mut App);
+
+type AddRequest = fn(&mut App);
+
+/// Whether a payload holds a request of the registered type.
+type Accepts = fn(&crate::protocol::Payload) -> bool;
+
+#[derive(Default)]
+pub struct Plugin {
+    channels: Vec<(u8, AddChannel)>,
+    requests: Vec<(std::any::TypeId, AddRequest, Accepts)>,
+}
+
+impl Plugin {
+    /// Registers the channel `C`, adding its [`crate::protocol::ChannelReceivedEvent`] and
+    /// [`crate::protocol::SendChannelEvent`].
+    ///
+    /// # Panics
+    ///
+    /// If the channel id is reserved or already registered.
+    #[must_use]
+    pub fn with_channel<C: crate::protocol::Channel>(mut self) -> Plugin {
+        assert!(
+            C::ID >= crate::protocol::FIRST_CHANNEL,
+            "channel {} uses reserved id {}",
+            std::any::type_name::<C>(),
+            C::ID
+        );
+        assert!(
+            self.channels.iter().all(|(id, _)| *id != C::ID),
+            "channel {} reuses id {}",
+            std::any::type_name::<C>(),
+            C::ID
+        );
+
+        self.channels.push((C::ID, add_channel::<C>));
+        self
+    }
+
+    /// Registers the request `R`, adding its [`crate::protocol::RequestEvent`] for the systems
+    /// answering it and its [`crate::protocol::ResponseEvent`] for the systems sending it.
+    ///
+    /// Requests the peer sends that no registered type accepts are answered with
+    /// [`crate::protocol::RpcError::Unhandled`].
+    ///
+    /// # Panics
+    ///
+    /// If the request is already registered.
+    #[must_use]
+    pub fn with_request<R: crate::protocol::Request>(mut self) -> Plugin {
+        let type_id = std::any::TypeId::of::<R>();
+        assert!(
+            self.requests.iter().all(|(id, _, _)| *id != type_id),
+            "request {} is already registered",
+            std::any::type_name::<R>()
+        );
+
+        self.requests
+            .push((type_id, add_request::<R>, accepts::<R>));
+        self
+    }
+}
 
 impl bevy::prelude::Plugin for Plugin {
     fn build(&self, app: &mut App) {
+        for (_, add_channel) in &self.channels {
+            add_channel(app);
+        }
+        for (_, add_request, _) in &self.requests {
+            add_request(app);
+        }
+
         app.add_event::<crate::protocol::ConnectionCreatedEvent>()
             .add_event::<crate::protocol::ConnectionDestroyedEvent>()
             .add_event::<crate::protocol::PayloadReceivedEvent>()
-            .add_system(multiplex)
-            .add_system(create_connection)
-            .add_system(destroy_connection)
-            .add_system(read_payload);
+            .add_event::<crate::protocol::DatagramReceivedEvent>()
+            .add_event::<crate::protocol::MessageReceivedEvent>()
+            .add_event::<crate::protocol::RequestReceivedEvent>()
+            .add_event::<crate::protocol::ResponseReceivedEvent>()
+            .add_event::<crate::protocol::SendPayloadEvent>()
+            .add_event::<crate::protocol::SendDatagramEvent>()
+            .add_event::<crate::protocol::SendRequestEvent>()
+            .add_event::<crate::protocol::SendResponseEvent>()
+            .add_event::<crate::protocol::NetworkShutdownEvent>()
+            .add_event::<crate::protocol::StatsMeasuredEvent>()
+            .add_event::<crate::protocol::CloseConnectionEvent>()
+            .init_resource::<MultiplexSettings>()
+            .init_resource::<MultiplexStatistics>()
+            .init_resource::<ResumeSettings>()
+            .init_resource::<RevokedTokens>()
+            .insert_resource(Channels {
+                ids: self.channels.iter().map(|(id, _)| *id).collect(),
+            })
+            .insert_resource(RequestHandlers {
+                handlers: self
+                    .requests
+                    .iter()
+                    .map(|&(type_id, _, accepts)| (type_id, accepts))
+                    .collect(),
+            })
+            .init_resource::<RequestIds>()
+            .add_system_to_stage(CoreStage::PreUpdate, multiplex.label(Label::Multiplex))
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                create_connection.after(Label::Multiplex),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                destroy_connection.after(Label::Multiplex),
+            )
+            .add_system_to_stage(CoreStage::PreUpdate, expire_connection)
+            .add_system_to_stage(CoreStage::PreUpdate, update_stats.after(Label::Multiplex))
+            .add_system_to_stage(CoreStage::PostUpdate, close_connection)
+            .add_system_to_stage(CoreStage::Last, record_metrics)
+            .add_system(read_payload)
+            .add_system(write_payload)
+            .add_system(read_datagram)
+            .add_system(write_datagram)
+            .add_system(write_request)
+            .add_system(write_response)
+            .add_system(reject_unhandled_requests)
+            .add_system(warn_unknown_channels);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, SystemLabel)]
+enum Label {
+    Multiplex,
+}
+
+/// Minimum time between two warnings about an exhausted [`MultiplexSettings::budget`].
+const OVERFLOW_WARNING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Controls how [`multiplex`] drains the transport event queue.
+#[derive(Default)]
+pub(crate) struct MultiplexSettings {
+    /// Maximum number of events forwarded per frame, unlimited when `None`.
+    pub(crate) budget: Option<usize>,
+}
+
+#[derive(Default)]
+pub(crate) struct MultiplexStatistics {
+    /// Number of events forwarded in the last frame.
+    pub(crate) events: usize,
+    /// Number of events forwarded since startup.
+    pub(crate) events_total: usize,
+    /// Number of frames that stopped draining because the budget was exhausted.
+    pub(crate) overflows: usize,
+    /// Whether every transport has dropped its end of the queue.
+    pub(crate) shutdown: bool,
+}
+
+/// Controls how long a disconnected peer can resume its [`Connection`].
+pub(crate) struct ResumeSettings {
+    pub(crate) timeout: std::time::Duration,
+}
+
+impl Default for ResumeSettings {
+    fn default() -> ResumeSettings {
+        ResumeSettings {
+            timeout: std::time::Duration::from_secs(30),
+        }
     }
 }
 
 #[derive(Component)]
-pub(crate) struct Connection {
+pub struct Connection {
     connection_id: usize,
-    _sender: tokio::sync::mpsc::UnboundedSender<crate::protocol::Payload>,
+    version: crate::protocol::Version,
+    resume_token: String,
+    peer_identity: Option<crate::protocol::PeerIdentity>,
+    remote_address: std::net::SocketAddr,
+    codec: crate::codec::Format,
+    created_at: std::time::Instant,
+    sender: tokio::sync::mpsc::UnboundedSender<crate::protocol::Outgoing>,
+}
+
+/// Link quality of a [`Connection`], refreshed by the transport about once a second and reset
+/// when the session resumes over a new transport connection.
+#[derive(Clone, Component, Debug, Default, Deref)]
+pub struct ConnectionStats(pub crate::protocol::TransportStats);
+
+/// Marks a [`Connection`] whose peer disconnected but may still resume it.
+///
+/// The entity is despawned once the resume timeout elapses.
+#[derive(Component)]
+pub struct Detached {
+    since: std::time::Instant,
+}
+
+/// Resume tokens of closed connections, a kicked peer does not get to come back with them.
+#[derive(Default)]
+struct RevokedTokens {
+    tokens: std::collections::HashSet<String>,
+}
+
+/// Ids of the channels registered with [`Plugin::with_channel`].
+struct Channels {
+    ids: std::collections::HashSet<u8>,
+}
+
+/// Requests answered by gameplay systems, by type.
+///
+/// Requests no registered type accepts are answered with
+/// [`crate::protocol::RpcError::Unhandled`].
+pub struct RequestHandlers {
+    handlers: std::collections::HashMap<std::any::TypeId, Accepts>,
+}
+
+impl RequestHandlers {
+    fn accepts(&self, payload: &crate::protocol::Payload) -> bool {
+        self.handlers.values().any(|accepts| accepts(payload))
+    }
+}
+
+#[derive(Default)]
+pub struct RequestIds {
+    next: u64,
+    /// Type of the requests awaiting a response, by request id.
+    pending: std::collections::HashMap<u64, std::any::TypeId>,
+}
+
+/// Sends requests to peers and answers theirs, the responses arrive as
+/// [`crate::protocol::ResponseEvent`] carrying the returned request id.
+#[derive(SystemParam)]
+pub struct Rpc<'w, 's> {
+    request_ids: ResMut<'w, RequestIds>,
+    handlers: Res<'w, RequestHandlers>,
+    requests: EventWriter<'w, 's, crate::protocol::SendRequestEvent>,
+    responses: EventWriter<'w, 's, crate::protocol::SendResponseEvent>,
+}
+
+impl Connection {
+    #[must_use]
+    pub fn connection_id(&self) -> usize {
+        self.connection_id
+    }
+
+    /// Protocol version negotiated during the handshake.
+    #[must_use]
+    pub fn version(&self) -> crate::protocol::Version {
+        self.version
+    }
+
+    /// Certificate identity of the peer, when mutual TLS is enabled.
+    #[must_use]
+    pub fn peer_identity(&self) -> Option<&crate::protocol::PeerIdentity> {
+        self.peer_identity.as_ref()
+    }
+
+    #[must_use]
+    pub fn remote_address(&self) -> std::net::SocketAddr {
+        self.remote_address
+    }
+
+    /// Codec negotiated through ALPN, payloads are already decoded but gameplay can still branch
+    /// on it, e.g. to budget for the larger JSON encoding.
+    #[must_use]
+    pub fn codec(&self) -> crate::codec::Format {
+        self.codec
+    }
+
+    /// Time since the session was created, including any resumed connections.
+    #[must_use]
+    pub fn uptime(&self) -> std::time::Duration {
+        self.created_at.elapsed()
+    }
+
+    /// Hands an instruction to the transport, returns false when it is gone.
+    pub(crate) fn send(&self, outgoing: crate::protocol::Outgoing) -> bool {
+        self.sender.send(outgoing).is_ok()
+    }
+}
+
+impl<'w, 's> Rpc<'w, 's> {
+    pub fn request<R: crate::protocol::Request>(
+        &mut self,
+        connection_id: usize,
+        request: R,
+    ) -> u64 {
+        self.request_with_timeout(connection_id, request, DEFAULT_REQUEST_TIMEOUT)
+    }
+
+    /// Sends a request registered with [`Plugin::with_request`], its response is dropped
+    /// otherwise.
+    pub fn request_with_timeout<R: crate::protocol::Request>(
+        &mut self,
+        connection_id: usize,
+        request: R,
+        timeout: std::time::Duration,
+    ) -> u64 {
+        let request_id = self.request_ids.next;
+        self.request_ids.next += 1;
+
+        // only registered requests have a system reading, and forgetting, their responses.
+        let type_id = std::any::TypeId::of::<R>();
+        if self.handlers.handlers.contains_key(&type_id) {
+            self.request_ids.pending.insert(request_id, type_id);
+        } else {
+            warn!(
+                request = std::any::type_name::<R>(),
+                "request not registered, its response is dropped"
+            );
+        }
+
+        self.requests.send(crate::protocol::SendRequestEvent {
+            connection_id,
+            request_id,
+            payload: request.into(),
+            timeout,
+        });
+
+        request_id
+    }
+
+    pub fn respond<R: crate::protocol::Request>(
+        &mut self,
+        request: &crate::protocol::RequestEvent<R>,
+        response: Result<R::Response, crate::protocol::RpcError>,
+    ) {
+        self.responses.send(crate::protocol::SendResponseEvent {
+            connection_id: request.connection_id,
+            request_id: request.request_id,
+            response: response.map(Into::into),
+        });
+    }
+}
+
+#[derive(SystemParam)]
+struct MultiplexWriters<'w, 's> {
+    connection_created: EventWriter<'w, 's, crate::protocol::ConnectionCreatedEvent>,
+    connection_destroyed: EventWriter<'w, 's, crate::protocol::ConnectionDestroyedEvent>,
+    payload_received: EventWriter<'w, 's, crate::protocol::PayloadReceivedEvent>,
+    datagram_received: EventWriter<'w, 's, crate::protocol::DatagramReceivedEvent>,
+    message_received: EventWriter<'w, 's, crate::protocol::MessageReceivedEvent>,
+    request_received: EventWriter<'w, 's, crate::protocol::RequestReceivedEvent>,
+    response_received: EventWriter<'w, 's, crate::protocol::ResponseReceivedEvent>,
+    stats_measured: EventWriter<'w, 's, crate::protocol::StatsMeasuredEvent>,
+    network_shutdown: EventWriter<'w, 's, crate::protocol::NetworkShutdownEvent>,
 }
 
 fn multiplex(
+    mut commands: Commands,
+    settings: Res<MultiplexSettings>,
+    mut statistics: ResMut<MultiplexStatistics>,
     mut receiver: ResMut<tokio::sync::mpsc::UnboundedReceiver<crate::protocol::Event>>,
-    mut connection_created_writer: EventWriter<crate::protocol::ConnectionCreatedEvent>,
-    mut connection_destroyed_writer: EventWriter<crate::protocol::ConnectionDestroyedEvent>,
-    mut payload_received_writer: EventWriter<crate::protocol::PayloadReceivedEvent>,
+    mut writers: MultiplexWriters,
+    mut held: Local<Option<crate::protocol::Event>>,
+    mut warned: Local<Option<(std::time::Instant, usize)>>,
 ) {
-    match receiver.try_recv() {
-        Ok(event) => match event {
-            crate::protocol::Event::ConnectionCreated(event) => {
-                connection_created_writer.send(event)
+    let mut events = 0;
+    let mut overflowed = false;
+
+    loop {
+        // an event over the last budget is forwarded first.
+        let received = match held.take() {
+            Some(event) => Ok(event),
+            None => receiver.try_recv(),
+        };
+
+        match received {
+            Ok(event) if matches!(settings.budget, Some(budget) if events >= budget) => {
+                *held = Some(event);
+                overflowed = true;
+                break;
             }
-            crate::protocol::Event::ConnectionDestroyed(event) => {
-                connection_destroyed_writer.send(event)
+            Ok(event) => {
+                events += 1;
+
+                match event {
+                    crate::protocol::Event::ConnectionCreated(event) => {
+                        writers.connection_created.send(event)
+                    }
+                    crate::protocol::Event::ConnectionDestroyed(event) => {
+                        writers.connection_destroyed.send(event)
+                    }
+                    crate::protocol::Event::ConnectionStateChanged(state) => {
+                        commands.insert_resource(state)
+                    }
+                    crate::protocol::Event::PayloadReceived(event) => {
+                        writers.payload_received.send(event)
+                    }
+                    crate::protocol::Event::MessageReceived(event) => {
+                        match event.channel {
+                            crate::protocol::PAYLOAD_CHANNEL => writers.payload_received.send(
+                                crate::protocol::PayloadReceivedEvent {
+                                    connection_id: event.connection_id,
+                                    payload: event.payload,
+                                },
+                            ),
+                            crate::protocol::DATAGRAM_CHANNEL => writers.datagram_received.send(
+                                crate::protocol::DatagramReceivedEvent {
+                                    connection_id: event.connection_id,
+                                    payload: event.payload,
+                                },
+                            ),
+                            _ => writers.message_received.send(event),
+                        }
+                    }
+                    crate::protocol::Event::RequestReceived(event) => {
+                        writers.request_received.send(event)
+                    }
+                    crate::protocol::Event::ResponseReceived(event) => {
+                        writers.response_received.send(event)
+                    }
+                    crate::protocol::Event::StatsMeasured(event) => {
+                        writers.stats_measured.send(event);
+                    }
+                }
+            }
+            Err(err) => {
+                match err {
+                    tokio::sync::mpsc::error::TryRecvError::Empty => {}
+                    tokio::sync::mpsc::error::TryRecvError::Disconnected => {
+                        if !statistics.shutdown {
+                            statistics.shutdown = true;
+                            warn!("network shutdown");
+                            writers
+                                .network_shutdown
+                                .send(crate::protocol::NetworkShutdownEvent);
+                        }
+                    }
+                }
+                break;
+            }
+        }
+    }
+
+    statistics.events = events;
+    statistics.events_total += events;
+
+    if overflowed {
+        statistics.overflows += 1;
+
+        // a sustained overload would warn every frame otherwise.
+        match &*warned {
+            Some((at, _)) if at.elapsed() < OVERFLOW_WARNING_INTERVAL => {}
+            _ => {
+                let overflows = statistics.overflows - warned.map_or(0, |(_, x)| x);
+                warn!(budget = events, overflows, "multiplex budget exceeded");
+                *warned = Some((std::time::Instant::now(), statistics.overflows));
             }
-            crate::protocol::Event::PayloadReceived(event) => payload_received_writer.send(event),
-        },
-        Err(err) => match err {
-            tokio::sync::mpsc::error::TryRecvError::Empty => {}
-            tokio::sync::mpsc::error::TryRecvError::Disconnected => {}
-        },
+        }
+    }
+
+    crate::metrics::metrics()
+        .network_events
+        .observe(events as f64);
+}
+
+fn record_metrics(time: Option<Res<Time>>, query: Query<Option<&Detached>, With<Connection>>) {
+    let metrics = crate::metrics::metrics();
+
+    let detached = query.iter().filter(Option::is_some).count();
+    metrics
+        .network_connections
+        .with_label_values(&["attached"])
+        .set((query.iter().count() - detached) as i64);
+    metrics
+        .network_connections
+        .with_label_values(&["detached"])
+        .set(detached as i64);
+
+    if let Some(time) = time {
+        metrics.network_frame_time.observe(time.delta_seconds_f64());
     }
 }
 
 fn create_connection(
     mut commands: Commands,
+    revoked: Res<RevokedTokens>,
+    mut query: Query<(Entity, &mut Connection, Option<&Detached>)>,
     mut reader: EventReader<crate::protocol::ConnectionCreatedEvent>,
 ) {
     for event in reader.iter() {
         let span = info_span!("connection", connection_id = event.connection_id);
         let _guard = span.enter();
 
-        info!("creating connection");
+        if revoked.tokens.contains(&event.resume_token) {
+            warn!("resume token revoked");
+
+            let _ = event.sender.send(crate::protocol::Outgoing::Close {
+                code: crate::protocol::CloseReason::Kicked,
+                reason: "session closed".into(),
+            });
+
+            continue;
+        }
+
+        let resumed = query.iter_mut().find(|(_, connection, _)| {
+            connection.resume_token == event.resume_token
+                && connection.peer_identity == event.peer_identity
+        });
+
+        match resumed {
+            Some((entity, mut connection, Some(_))) => {
+                info!(
+                    version = ?event.version,
+                    previous_connection_id = connection.connection_id,
+                    "resuming connection"
+                );
+
+                connection.connection_id = event.connection_id;
+                connection.version = event.version;
+                connection.remote_address = event.remote_address;
+                connection.codec = event.codec;
+                connection.sender = event.sender.clone();
+
+                commands
+                    .entity(entity)
+                    .insert(Name::new(format!("connection {}", event.connection_id)))
+                    .insert(ConnectionStats::default())
+                    .remove::<Detached>();
+
+                continue;
+            }
+            // an attached session is never taken over, the client retries once the previous
+            // transport has noticed the disconnect.
+            Some((_, connection, None)) => {
+                warn!(
+                    attached_connection_id = connection.connection_id,
+                    "session already attached"
+                );
+
+                let _ = event.sender.send(crate::protocol::Outgoing::Close {
+                    code: crate::protocol::CloseReason::SessionAttached,
+                    reason: "session already attached".into(),
+                });
+
+                continue;
+            }
+            None => {}
+        }
+
+        info!(version = ?event.version, "creating connection");
 
         let connection = Connection {
             connection_id: event.connection_id,
-            _sender: event.sender.clone(),
+            version: event.version,
+            resume_token: event.resume_token.clone(),
+            peer_identity: event.peer_identity.clone(),
+            remote_address: event.remote_address,
+            codec: event.codec,
+            created_at: std::time::Instant::now(),
+            sender: event.sender.clone(),
         };
 
         commands
@@ -64,23 +556,85 @@ fn create_connection(
                 "connection {}",
                 connection.connection_id
             )))
-            .insert(connection);
+            .insert(connection)
+            .insert(ConnectionStats::default());
     }
 }
 
 fn destroy_connection(
     mut commands: Commands,
-    mut query: Query<(Entity, &Connection)>,
+    query: Query<(Entity, &Connection), Without<Detached>>,
     mut reader: EventReader<crate::protocol::ConnectionDestroyedEvent>,
 ) {
     for event in reader.iter() {
-        for (entity, connection) in query.iter_mut() {
+        for (entity, connection) in query.iter() {
             let span = info_span!("connection", connection_id = connection.connection_id);
             let _guard = span.enter();
 
             if connection.connection_id == event.connection_id {
-                info!("destroying connection");
-                commands.entity(entity).despawn();
+                info!("detaching connection");
+                commands.entity(entity).insert(Detached {
+                    since: std::time::Instant::now(),
+                });
+            }
+        }
+    }
+}
+
+fn expire_connection(
+    mut commands: Commands,
+    settings: Res<ResumeSettings>,
+    query: Query<(Entity, &Connection, &Detached)>,
+) {
+    for (entity, connection, detached) in query.iter() {
+        if detached.since.elapsed() < settings.timeout {
+            continue;
+        }
+
+        let span = info_span!("connection", connection_id = connection.connection_id);
+        let _guard = span.enter();
+
+        info!("destroying connection");
+        commands.entity(entity).despawn();
+    }
+}
+
+fn close_connection(
+    mut commands: Commands,
+    mut revoked: ResMut<RevokedTokens>,
+    query: Query<(Entity, &Connection)>,
+    mut reader: EventReader<crate::protocol::CloseConnectionEvent>,
+) {
+    for event in reader.iter() {
+        for (entity, connection) in query.iter() {
+            if connection.connection_id != event.connection_id {
+                continue;
+            }
+
+            let span = info_span!("connection", connection_id = connection.connection_id);
+            let _guard = span.enter();
+
+            info!(reason = event.reason, "closing connection");
+
+            // a detached connection has no transport left to notify.
+            let _ = connection.sender.send(crate::protocol::Outgoing::Close {
+                code: crate::protocol::CloseReason::Kicked,
+                reason: event.reason.clone(),
+            });
+            revoked.tokens.insert(connection.resume_token.clone());
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+fn update_stats(
+    mut query: Query<(&Connection, &mut ConnectionStats)>,
+    mut reader: EventReader<crate::protocol::StatsMeasuredEvent>,
+) {
+    for event in reader.iter() {
+        for (connection, mut stats) in query.iter_mut() {
+            if connection.connection_id == event.connection_id {
+                stats.0 = event.stats.clone();
             }
         }
     }
@@ -94,3 +648,715 @@ fn read_payload(mut reader: EventReader<crate::protocol::PayloadReceivedEvent>)
         debug!(payload = ?event.payload, "read");
     }
 }
+
+fn write_payload(
+    query: Query<&Connection, Without<Detached>>,
+    mut reader: EventReader<crate::protocol::SendPayloadEvent>,
+) {
+    for event in reader.iter() {
+        write_message(
+            &query,
+            &event.target,
+            crate::protocol::PAYLOAD_CHANNEL,
+            crate::protocol::Reliability::ReliableUnordered,
+            &event.payload,
+        );
+    }
+}
+
+fn read_datagram(mut reader: EventReader<crate::protocol::DatagramReceivedEvent>) {
+    for event in reader.iter() {
+        let span = info_span!("connection", connection_id = ?event.connection_id);
+        let _guard = span.enter();
+
+        trace!(payload = ?event.payload, "read datagram");
+    }
+}
+
+fn write_datagram(
+    query: Query<&Connection, Without<Detached>>,
+    mut reader: EventReader<crate::protocol::SendDatagramEvent>,
+) {
+    for event in reader.iter() {
+        write_message(
+            &query,
+            &event.target,
+            crate::protocol::DATAGRAM_CHANNEL,
+            crate::protocol::Reliability::Unreliable,
+            &event.payload,
+        );
+    }
+}
+
+fn add_channel<C: crate::protocol::Channel>(app: &mut App) {
+    app.add_event::<crate::protocol::ChannelReceivedEvent<C>>()
+        .add_event::<crate::protocol::SendChannelEvent<C>>()
+        .add_system(read_channel::<C>)
+        .add_system(write_channel::<C>);
+}
+
+fn add_request<R: crate::protocol::Request>(app: &mut App) {
+    app.add_event::<crate::protocol::RequestEvent<R>>()
+        .add_event::<crate::protocol::ResponseEvent<R>>()
+        .add_system(read_request::<R>)
+        .add_system(read_response::<R>);
+}
+
+fn accepts<R: crate::protocol::Request>(payload: &crate::protocol::Payload) -> bool {
+    R::try_from(payload.clone()).is_ok()
+}
+
+fn read_request<R: crate::protocol::Request>(
+    mut reader: EventReader<crate::protocol::RequestReceivedEvent>,
+    mut writer: EventWriter<crate::protocol::RequestEvent<R>>,
+) {
+    for event in reader.iter() {
+        if let Ok(request) = R::try_from(event.payload.clone()) {
+            writer.send(crate::protocol::RequestEvent {
+                connection_id: event.connection_id,
+                request_id: event.request_id,
+                request,
+            });
+        }
+    }
+}
+
+fn read_response<R: crate::protocol::Request>(
+    mut request_ids: ResMut<RequestIds>,
+    mut reader: EventReader<crate::protocol::ResponseReceivedEvent>,
+    mut writer: EventWriter<crate::protocol::ResponseEvent<R>>,
+) {
+    for event in reader.iter() {
+        match request_ids.pending.get(&event.request_id) {
+            Some(type_id) if *type_id == std::any::TypeId::of::<R>() => {
+                request_ids.pending.remove(&event.request_id);
+            }
+            _ => continue,
+        }
+
+        let response = event.response.clone().and_then(|payload| {
+            R::Response::try_from(payload).map_err(|payload| {
+                crate::protocol::RpcError::Failed(format!("unexpected {} response", payload.kind()))
+            })
+        });
+
+        writer.send(crate::protocol::ResponseEvent {
+            connection_id: event.connection_id,
+            request_id: event.request_id,
+            response,
+        });
+    }
+}
+
+fn read_channel<C: crate::protocol::Channel>(
+    mut reader: EventReader<crate::protocol::MessageReceivedEvent>,
+    mut writer: EventWriter<crate::protocol::ChannelReceivedEvent<C>>,
+) {
+    for event in reader.iter() {
+        if event.channel == C::ID {
+            writer.send(crate::protocol::ChannelReceivedEvent::new(
+                event.connection_id,
+                event.payload.clone(),
+            ));
+        }
+    }
+}
+
+/// Logs messages no registered channel reads, ids below [`crate::protocol::FIRST_CHANNEL`]
+/// belong to other plugins.
+fn warn_unknown_channels(
+    channels: Res<Channels>,
+    mut reader: EventReader<crate::protocol::MessageReceivedEvent>,
+) {
+    for event in reader.iter() {
+        if event.channel < crate::protocol::FIRST_CHANNEL || channels.ids.contains(&event.channel) {
+            continue;
+        }
+
+        let span = info_span!("connection", connection_id = event.connection_id);
+        let _guard = span.enter();
+
+        warn!(
+            channel = event.channel,
+            kind = event.payload.kind(),
+            "message on unknown channel dropped"
+        );
+    }
+}
+
+fn write_channel<C: crate::protocol::Channel>(
+    query: Query<&Connection, Without<Detached>>,
+    mut reader: EventReader<crate::protocol::SendChannelEvent<C>>,
+) {
+    for event in reader.iter() {
+        write_message(&query, &event.target, C::ID, C::RELIABILITY, &event.payload);
+    }
+}
+
+fn write_message(
+    query: &Query<&Connection, Without<Detached>>,
+    target: &crate::protocol::Target,
+    channel: u8,
+    reliability: crate::protocol::Reliability,
+    payload: &crate::protocol::Payload,
+) {
+    for connection in query.iter() {
+        if !target.contains(connection.connection_id) {
+            continue;
+        }
+
+        let span = info_span!("connection", connection_id = connection.connection_id);
+        let _guard = span.enter();
+
+        trace!(channel = channel, payload = ?payload, "write");
+
+        if connection
+            .sender
+            .send(crate::protocol::Outgoing::Message {
+                channel,
+                reliability,
+                payload: payload.clone(),
+            })
+            .is_err()
+        {
+            warn!("connection closed");
+        }
+    }
+}
+
+fn write_request(
+    query: Query<&Connection, Without<Detached>>,
+    mut reader: EventReader<crate::protocol::SendRequestEvent>,
+    mut writer: EventWriter<crate::protocol::ResponseReceivedEvent>,
+) {
+    for event in reader.iter() {
+        let span = info_span!("connection", connection_id = event.connection_id);
+        let _guard = span.enter();
+
+        debug!(request_id = event.request_id, payload = ?event.payload, "request");
+
+        let connection = query
+            .iter()
+            .find(|connection| connection.connection_id == event.connection_id);
+
+        let sent = match connection {
+            Some(connection) => connection
+                .sender
+                .send(crate::protocol::Outgoing::Request {
+                    request_id: event.request_id,
+                    payload: event.payload.clone(),
+                    timeout: event.timeout,
+                })
+                .is_ok(),
+            None => false,
+        };
+
+        if !sent {
+            warn!(request_id = event.request_id, "connection closed");
+
+            writer.send(crate::protocol::ResponseReceivedEvent {
+                connection_id: event.connection_id,
+                request_id: event.request_id,
+                response: Err(crate::protocol::RpcError::Closed),
+            });
+        }
+    }
+}
+
+fn write_response(
+    query: Query<&Connection, Without<Detached>>,
+    mut reader: EventReader<crate::protocol::SendResponseEvent>,
+) {
+    for event in reader.iter() {
+        let span = info_span!("connection", connection_id = event.connection_id);
+        let _guard = span.enter();
+
+        debug!(request_id = event.request_id, response = ?event.response, "response");
+
+        let connection = query
+            .iter()
+            .find(|connection| connection.connection_id == event.connection_id);
+
+        if let Some(connection) = connection {
+            if connection
+                .sender
+                .send(crate::protocol::Outgoing::Response {
+                    request_id: event.request_id,
+                    response: event.response.clone(),
+                })
+                .is_err()
+            {
+                warn!("connection closed");
+            }
+        }
+    }
+}
+
+fn reject_unhandled_requests(
+    handlers: Res<RequestHandlers>,
+    mut reader: EventReader<crate::protocol::RequestReceivedEvent>,
+    mut writer: EventWriter<crate::protocol::SendResponseEvent>,
+) {
+    for event in reader.iter() {
+        if handlers.accepts(&event.payload) {
+            continue;
+        }
+
+        let span = info_span!("connection", connection_id = event.connection_id);
+        let _guard = span.enter();
+
+        warn!(kind = event.payload.kind(), "unhandled request");
+
+        writer.send(crate::protocol::SendResponseEvent {
+            connection_id: event.connection_id,
+            request_id: event.request_id,
+            response: Err(crate::protocol::RpcError::Unhandled),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::Scripted;
+    use crate::protocol::{
+        Channel, Outgoing, Payload, Reliability, SendChannelEvent, Target, Version, Version1,
+    };
+
+    #[derive(Debug)]
+    struct Ordered;
+
+    impl Channel for Ordered {
+        const ID: u8 = crate::protocol::FIRST_CHANNEL;
+        const RELIABILITY: Reliability = Reliability::ReliableOrdered;
+    }
+
+    #[derive(Debug)]
+    struct Reserved;
+
+    impl Channel for Reserved {
+        const ID: u8 = crate::protocol::DATAGRAM_CHANNEL;
+        const RELIABILITY: Reliability = Reliability::Unreliable;
+    }
+
+    fn received(
+        sender: &tokio::sync::mpsc::UnboundedSender<crate::protocol::Event>,
+        events: usize,
+    ) {
+        for connection_id in 0..events {
+            sender
+                .send(crate::protocol::Event::PayloadReceived(
+                    crate::protocol::PayloadReceivedEvent {
+                        connection_id,
+                        payload: Payload::V1(Version1::Ping),
+                    },
+                ))
+                .unwrap();
+        }
+    }
+
+    fn statistics(app: &App) -> (usize, usize, usize) {
+        let statistics = app.world.resource::<MultiplexStatistics>();
+        (
+            statistics.events,
+            statistics.events_total,
+            statistics.overflows,
+        )
+    }
+
+    #[test]
+    fn test_multiplex() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut app = App::new();
+        app.insert_resource(receiver).add_plugin(Plugin::default());
+
+        received(&sender, 100);
+        app.update();
+        assert_eq!(statistics(&app), (100, 100, 0));
+
+        app.update();
+        assert_eq!(statistics(&app), (0, 100, 0));
+    }
+
+    #[test]
+    fn test_multiplex_budget() {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut app = App::new();
+        app.insert_resource(receiver)
+            .insert_resource(MultiplexSettings { budget: Some(3) })
+            .add_plugin(Plugin::default());
+
+        // exactly the budget is not an overflow.
+        received(&sender, 3);
+        app.update();
+        assert_eq!(statistics(&app), (3, 3, 0));
+
+        received(&sender, 7);
+        app.update();
+        assert_eq!(statistics(&app), (3, 6, 1));
+        app.update();
+        assert_eq!(statistics(&app), (3, 9, 2));
+        app.update();
+        assert_eq!(statistics(&app), (1, 10, 2));
+
+        // events of the last two frames come out in order, none lost when held over.
+        let events = app
+            .world
+            .resource::<Events<crate::protocol::PayloadReceivedEvent>>();
+        let mut reader = events.get_reader();
+        let connection_ids = reader
+            .iter(events)
+            .map(|event| event.connection_id)
+            .collect::<Vec<_>>();
+        assert_eq!(connection_ids, [3, 4, 5, 6]);
+    }
+
+    /// A request answered with the next number.
+    #[derive(Debug, PartialEq)]
+    struct Increment(u64);
+
+    impl From<Increment> for Payload {
+        fn from(increment: Increment) -> Payload {
+            Payload::V1(Version1::Acknowledge(increment.0))
+        }
+    }
+
+    impl TryFrom<Payload> for Increment {
+        type Error = Payload;
+
+        fn try_from(payload: Payload) -> Result<Increment, Payload> {
+            match payload {
+                Payload::V1(Version1::Acknowledge(x)) => Ok(Increment(x)),
+                payload => Err(payload),
+            }
+        }
+    }
+
+    impl crate::protocol::Request for Increment {
+        type Response = Increment;
+    }
+
+    /// A request the server does not handle.
+    #[derive(Debug, PartialEq)]
+    struct Pong;
+
+    impl From<Pong> for Payload {
+        fn from(_: Pong) -> Payload {
+            Payload::V1(Version1::Pong)
+        }
+    }
+
+    impl TryFrom<Payload> for Pong {
+        type Error = Payload;
+
+        fn try_from(payload: Payload) -> Result<Pong, Payload> {
+            match payload {
+                Payload::V1(Version1::Pong) => Ok(Pong),
+                payload => Err(payload),
+            }
+        }
+    }
+
+    impl crate::protocol::Request for Pong {
+        type Response = Pong;
+    }
+
+    fn increment(mut rpc: Rpc, mut reader: EventReader<crate::protocol::RequestEvent<Increment>>) {
+        for event in reader.iter() {
+            rpc.respond(event, Ok(Increment(event.request.0 + 1)));
+        }
+    }
+
+    #[test]
+    fn test_rpc() {
+        let mut fixture = crate::loopback::Fixture::new(crate::codec::Format::Json, |app| {
+            app.add_plugin(Plugin::default().with_request::<Increment>())
+                .add_system(increment);
+        });
+        fixture.add_client(|app| {
+            app.add_plugin(
+                Plugin::default()
+                    .with_request::<Increment>()
+                    .with_request::<Pong>(),
+            );
+        });
+        fixture.step();
+
+        let client_app = &mut fixture.clients[0];
+        let connection_id = client_app
+            .world
+            .query::<&Connection>()
+            .single(&client_app.world)
+            .connection_id();
+
+        let mut state = bevy::ecs::system::SystemState::<Rpc>::new(&mut client_app.world);
+        let mut rpc = state.get_mut(&mut client_app.world);
+        let incremented = rpc.request(connection_id, Increment(41));
+        let unhandled = rpc.request(connection_id, Pong);
+
+        let mut incremented_reader = client_app
+            .world
+            .resource::<Events<crate::protocol::ResponseEvent<Increment>>>()
+            .get_reader();
+        let mut unhandled_reader = client_app
+            .world
+            .resource::<Events<crate::protocol::ResponseEvent<Pong>>>()
+            .get_reader();
+        let mut responses = Vec::new();
+
+        // the client sends, the server answers, then the client reads the responses.
+        for _ in 0..6 {
+            fixture.step();
+
+            let client_app = &fixture.clients[0];
+            let events = client_app
+                .world
+                .resource::<Events<crate::protocol::ResponseEvent<Increment>>>();
+            responses.extend(incremented_reader.iter(events).map(|event| {
+                (
+                    event.request_id,
+                    event.response.as_ref().map(|x| x.0).map_err(Clone::clone),
+                )
+            }));
+            let events = client_app
+                .world
+                .resource::<Events<crate::protocol::ResponseEvent<Pong>>>();
+            responses.extend(unhandled_reader.iter(events).map(|event| {
+                (
+                    event.request_id,
+                    event.response.as_ref().map(|_| 0).map_err(Clone::clone),
+                )
+            }));
+        }
+
+        responses.sort_by_key(|(request_id, _)| *request_id);
+        assert_eq!(
+            responses,
+            [
+                (incremented, Ok(42)),
+                (unhandled, Err(crate::protocol::RpcError::Unhandled))
+            ]
+        );
+        assert!(fixture.clients[0]
+            .world
+            .resource::<RequestIds>()
+            .pending
+            .is_empty());
+    }
+
+    #[test]
+    fn test_unregistered_request() {
+        let (_sender, receiver) = tokio::sync::mpsc::unbounded_channel::<crate::protocol::Event>();
+
+        let mut app = App::new();
+        app.insert_resource(receiver)
+            .add_plugin(Plugin::default().with_request::<Increment>());
+
+        let mut state = bevy::ecs::system::SystemState::<Rpc>::new(&mut app.world);
+        let mut rpc = state.get_mut(&mut app.world);
+        let incremented = rpc.request(1, Increment(41));
+        rpc.request(1, Pong);
+
+        // nothing would ever read the response of an unregistered request.
+        let pending = &app.world.resource::<RequestIds>().pending;
+        assert_eq!(pending.keys().copied().collect::<Vec<_>>(), [incremented]);
+    }
+
+    #[test]
+    fn test_channel() {
+        let (_sender, receiver) = tokio::sync::mpsc::unbounded_channel::<crate::protocol::Event>();
+        let (sender, mut outgoing) = tokio::sync::mpsc::unbounded_channel();
+
+        let mut app = App::new();
+        app.insert_resource(receiver)
+            .add_plugin(Plugin::default().with_channel::<Ordered>());
+
+        app.world.spawn().insert(Connection {
+            connection_id: 1,
+            version: Version::V1,
+            resume_token: String::new(),
+            peer_identity: None,
+            remote_address: ([127, 0, 0, 1], 4433).into(),
+            codec: crate::codec::Format::Json,
+            created_at: std::time::Instant::now(),
+            sender,
+        });
+
+        app.world
+            .resource_mut::<Events<SendChannelEvent<Ordered>>>()
+            .send(SendChannelEvent::new(
+                Target::Broadcast,
+                Payload::V1(Version1::Ping),
+            ));
+        app.update();
+
+        match outgoing.try_recv().unwrap() {
+            Outgoing::Message {
+                channel,
+                reliability,
+                payload,
+            } => {
+                assert_eq!(channel, Ordered::ID);
+                assert_eq!(reliability, Reliability::ReliableOrdered);
+                assert_eq!(payload, Payload::V1(Version1::Ping));
+            }
+            outgoing => panic!("unexpected {outgoing:?}"),
+        }
+    }
+
+    fn setup(app: &mut App) {
+        app.add_plugin(Plugin::default());
+    }
+
+    #[test]
+    fn test_resume() {
+        let mut server = Scripted::new(setup);
+        let _first = server.connect(1, "token", None);
+        server.disconnect(1);
+
+        assert_eq!(
+            server
+                .app
+                .world
+                .query_filtered::<&Connection, With<Detached>>()
+                .iter(&server.app.world)
+                .count(),
+            1
+        );
+
+        let _second = server.connect(2, "token", None);
+
+        let connections = server
+            .app
+            .world
+            .query_filtered::<&Connection, Without<Detached>>()
+            .iter(&server.app.world)
+            .map(Connection::connection_id)
+            .collect::<Vec<_>>();
+        assert_eq!(connections, vec![2]);
+    }
+
+    #[test]
+    fn test_resume_attached() {
+        let mut server = Scripted::new(setup);
+        let mut first = server.connect(1, "token", None);
+        let mut second = server.connect(2, "token", None);
+
+        let connections = server
+            .app
+            .world
+            .query_filtered::<&Connection, Without<Detached>>()
+            .iter(&server.app.world)
+            .map(Connection::connection_id)
+            .collect::<Vec<_>>();
+        assert_eq!(connections, vec![1]);
+
+        assert!(first.try_recv().is_err());
+        match second.try_recv().unwrap() {
+            Outgoing::Close { code, .. } => {
+                assert_eq!(code, crate::protocol::CloseReason::SessionAttached);
+            }
+            outgoing => panic!("unexpected {outgoing:?}"),
+        }
+    }
+
+    #[test]
+    fn test_resume_other_identity() {
+        let mut server = Scripted::new(setup);
+        let identity = |subject: &str| {
+            Some(crate::protocol::PeerIdentity {
+                subject: subject.into(),
+                subject_alt_names: Vec::new(),
+            })
+        };
+        let _alice = server.connect(1, "token", identity("CN=alice"));
+        let _mallory = server.connect(2, "token", identity("CN=mallory"));
+
+        let mut connections = server
+            .app
+            .world
+            .query::<&Connection>()
+            .iter(&server.app.world)
+            .map(Connection::connection_id)
+            .collect::<Vec<_>>();
+        connections.sort_unstable();
+        assert_eq!(connections, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_close_connection() {
+        let mut server = Scripted::new(setup);
+        let mut outgoing = server.connect(1, "token", None);
+
+        server
+            .app
+            .world
+            .resource_mut::<Events<crate::protocol::CloseConnectionEvent>>()
+            .send(crate::protocol::CloseConnectionEvent {
+                connection_id: 1,
+                reason: "cheating".into(),
+            });
+        server.app.update();
+
+        let count = |server: &mut Scripted| {
+            server
+                .app
+                .world
+                .query::<&Connection>()
+                .iter(&server.app.world)
+                .count()
+        };
+        assert_eq!(count(&mut server), 0);
+        match outgoing.try_recv().unwrap() {
+            crate::protocol::Outgoing::Close { code, reason } => {
+                assert_eq!(code, crate::protocol::CloseReason::Kicked);
+                assert_eq!(reason, "cheating");
+            }
+            outgoing => panic!("unexpected {outgoing:?}"),
+        }
+
+        // the kicked peer cannot come back with its resume token.
+        let mut outgoing = server.connect(2, "token", None);
+
+        assert_eq!(count(&mut server), 0);
+        assert!(matches!(
+            outgoing.try_recv().unwrap(),
+            crate::protocol::Outgoing::Close { .. }
+        ));
+    }
+
+    #[test]
+    fn test_update_stats() {
+        let mut server = Scripted::new(setup);
+        let _outgoing = server.connect(1, "token", None);
+
+        let stats = crate::protocol::TransportStats {
+            rtt: Some(std::time::Duration::from_millis(40)),
+            smoothed_rtt: std::time::Duration::from_millis(35),
+            messages_received: 3,
+            ..Default::default()
+        };
+        server.send(crate::protocol::Event::StatsMeasured(
+            crate::protocol::StatsMeasuredEvent {
+                connection_id: 1,
+                stats: stats.clone(),
+            },
+        ));
+
+        let result = server
+            .app
+            .world
+            .query::<&ConnectionStats>()
+            .single(&server.app.world)
+            .clone();
+        assert_eq!(result.0, stats);
+    }
+
+    #[test]
+    #[should_panic(expected = "reserved id")]
+    fn test_channel_reserved() {
+        let _ = Plugin::default().with_channel::<Reserved>();
+    }
+}
@@ -2,13 +2,53 @@
 pub enum Event {
     ConnectionCreated(ConnectionCreatedEvent),
     ConnectionDestroyed(ConnectionDestroyedEvent),
+    ConnectionStateChanged(ConnectionState),
     PayloadReceived(PayloadReceivedEvent),
+    MessageReceived(MessageReceivedEvent),
+    RequestReceived(RequestReceivedEvent),
+    ResponseReceived(ResponseReceivedEvent),
+    StatsMeasured(StatsMeasuredEvent),
+}
+
+/// Instructions sent from the network plugin to a connection.
+#[derive(Debug)]
+pub enum Outgoing {
+    Message {
+        channel: u8,
+        reliability: Reliability,
+        payload: Payload,
+    },
+    Request {
+        request_id: u64,
+        payload: Payload,
+        timeout: std::time::Duration,
+    },
+    Response {
+        request_id: u64,
+        response: Result<Payload, RpcError>,
+    },
+    /// Closes the connection with `code` and the given reason.
+    Close { code: CloseReason, reason: String },
 }
 
 #[derive(Debug)]
 pub struct ConnectionCreatedEvent {
     pub connection_id: usize,
-    pub sender: tokio::sync::mpsc::UnboundedSender<crate::protocol::Payload>,
+    pub version: Version,
+    /// Identifies the session across reconnections, see [`Handshake::Hello`].
+    pub resume_token: String,
+    pub peer_identity: Option<PeerIdentity>,
+    pub remote_address: std::net::SocketAddr,
+    /// Codec negotiated through ALPN.
+    pub codec: crate::codec::Format,
+    pub sender: tokio::sync::mpsc::UnboundedSender<crate::protocol::Outgoing>,
+}
+
+/// Verified certificate the peer presented during the TLS handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerIdentity {
+    pub subject: String,
+    pub subject_alt_names: Vec<String>,
 }
 
 #[derive(Debug)]
@@ -16,20 +56,268 @@ pub struct ConnectionDestroyedEvent {
     pub connection_id: usize,
 }
 
+/// Closes a connection without letting the peer resume its session.
+#[derive(Debug)]
+pub struct CloseConnectionEvent {
+    pub connection_id: usize,
+    pub reason: String,
+}
+
+#[derive(Debug)]
+pub struct StatsMeasuredEvent {
+    pub connection_id: usize,
+    pub stats: TransportStats,
+}
+
+/// Snapshot of a transport connection, counters are totals since it was established.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TransportStats {
+    /// Round trip of the latest keep-alive ping, `None` until the peer answered one.
+    pub rtt: Option<std::time::Duration>,
+    /// Round trip time smoothed by QUIC.
+    pub smoothed_rtt: std::time::Duration,
+    /// Congestion window in bytes.
+    pub congestion_window: u64,
+    /// Times the congestion controller reacted to lost packets, quinn does not count the
+    /// packets themselves.
+    pub congestion_events: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub packets_sent: u64,
+    pub packets_received: u64,
+    /// Payloads of the network plugin, keep-alive pings and their answers are not counted.
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+/// State of the client's link to the server, available as a resource once connecting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting { attempt: u32 },
+    Failed,
+}
+
 #[derive(Debug)]
 pub struct PayloadReceivedEvent {
     pub connection_id: usize,
     pub payload: Payload,
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+/// A payload that arrived unreliably, see [`SendDatagramEvent`].
+#[derive(Debug)]
+pub struct DatagramReceivedEvent {
+    pub connection_id: usize,
+    pub payload: Payload,
+}
+
+/// A payload received on a channel, before it is routed to its channel specific event.
+#[derive(Debug)]
+pub struct MessageReceivedEvent {
+    pub connection_id: usize,
+    pub channel: u8,
+    pub payload: Payload,
+}
+
+/// A payload received on the channel `C`, see [`Channel`].
+#[derive(Debug)]
+pub struct ChannelReceivedEvent<C: Channel> {
+    pub connection_id: usize,
+    pub payload: Payload,
+    channel: std::marker::PhantomData<C>,
+}
+
+/// A request of type `R` from the peer, answered with [`crate::network::Rpc::respond`].
+#[derive(Debug)]
+pub struct RequestEvent<R: Request> {
+    pub connection_id: usize,
+    pub request_id: u64,
+    pub request: R,
+}
+
+/// The outcome of a request of type `R` sent with [`crate::network::Rpc::request`].
+#[derive(Debug)]
+pub struct ResponseEvent<R: Request> {
+    pub connection_id: usize,
+    pub request_id: u64,
+    pub response: Result<R::Response, RpcError>,
+}
+
+/// A request from the peer awaiting a [`SendResponseEvent`] with the same ids.
+#[derive(Debug)]
+pub struct RequestReceivedEvent {
+    pub connection_id: usize,
+    pub request_id: u64,
+    pub payload: Payload,
+}
+
+/// The outcome of a [`SendRequestEvent`] with the same ids.
+#[derive(Debug)]
+pub struct ResponseReceivedEvent {
+    pub connection_id: usize,
+    pub request_id: u64,
+    pub response: Result<Payload, RpcError>,
+}
+
+#[derive(Debug)]
+pub struct NetworkShutdownEvent;
+
+/// Sends a payload unreliably and unordered, for state that is stale once superseded.
+///
+/// When the peer does not accept datagrams, or the payload does not fit in one, it is sent
+/// reliably instead and still surfaces as a [`DatagramReceivedEvent`] on the peer.
+#[derive(Debug)]
+pub struct SendDatagramEvent {
+    pub target: Target,
+    pub payload: Payload,
+}
+
+/// Sends a payload on the channel `C` with the channel's reliability.
+#[derive(Debug)]
+pub struct SendChannelEvent<C: Channel> {
+    pub target: Target,
+    pub payload: Payload,
+    channel: std::marker::PhantomData<C>,
+}
+
+#[derive(Debug)]
+pub struct SendRequestEvent {
+    pub connection_id: usize,
+    pub request_id: u64,
+    pub payload: Payload,
+    pub timeout: std::time::Duration,
+}
+
+#[derive(Debug)]
+pub struct SendResponseEvent {
+    pub connection_id: usize,
+    pub request_id: u64,
+    pub response: Result<Payload, RpcError>,
+}
+
+#[derive(Debug)]
+pub struct SendPayloadEvent {
+    pub target: Target,
+    pub payload: Payload,
+}
+
+#[derive(Debug)]
+pub enum Target {
+    Connection(usize),
+    Connections(Vec<usize>),
+    Broadcast,
+}
+
+/// Delivery guarantees of a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reliability {
+    /// Delivered in send order on a single long lived stream.
+    ReliableOrdered,
+    /// Delivered in any order, each payload on its own stream.
+    ReliableUnordered,
+    /// May be lost, duplicated or reordered, sent as datagrams when the peer supports them.
+    Unreliable,
+}
+
+/// A named class of payloads sharing a reliability, registered on [`crate::network::Plugin`].
+pub trait Channel: std::fmt::Debug + Send + Sync + 'static {
+    /// Identifies the channel on the wire, must be at least [`FIRST_CHANNEL`].
+    const ID: u8;
+    const RELIABILITY: Reliability;
+}
+
+/// A request answered by the peer, registered on [`crate::network::Plugin`].
+///
+/// Requests and responses travel as [`Payload`]s, converting back fails with the payload when
+/// it holds another message.
+pub trait Request:
+    Into<Payload> + TryFrom<Payload, Error = Payload> + std::fmt::Debug + Send + Sync + 'static
+{
+    type Response: Into<Payload>
+        + TryFrom<Payload, Error = Payload>
+        + std::fmt::Debug
+        + Send
+        + Sync
+        + 'static;
+}
+
+/// Carries [`SendPayloadEvent`] and [`PayloadReceivedEvent`].
+pub const PAYLOAD_CHANNEL: u8 = 0;
+
+/// Carries [`SendDatagramEvent`] and [`DatagramReceivedEvent`].
+pub const DATAGRAM_CHANNEL: u8 = 1;
+
+/// Carries [`Replication`] from the server, see [`crate::replication`].
+pub const REPLICATION_CHANNEL: u8 = 2;
+
+/// Carries [`Input`] from clients, see [`crate::replication::prediction`].
+pub const INPUT_CHANNEL: u8 = 3;
+
+/// Lowest id available to [`Channel`] implementations.
+pub const FIRST_CHANNEL: u8 = 4;
+
+/// Largest encoded payload a transport carries in a single frame or response.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Exchanged once on the first bidirectional stream, before any payload.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "type", content = "message")]
+pub enum Handshake {
+    /// Sent by the client, listing every version it can speak and the token of the session
+    /// it wants to resume.
+    #[serde(rename = "hello")]
+    Hello {
+        versions: Vec<Version>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume_token: Option<String>,
+    },
+
+    /// Sent by the server with the version both peers will use and the session token.
+    #[serde(rename = "accept")]
+    Accept {
+        version: Version,
+        resume_token: String,
+    },
+
+    /// Sent by the server when no common version exists.
+    #[serde(rename = "reject")]
+    Reject { versions: Vec<Version> },
+}
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize, serde::Serialize,
+)]
+pub enum Version {
+    #[serde(rename = "1")]
+    V1,
+}
+
+/// Application close codes sent to the peer when a connection is terminated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    IncompatibleVersion,
+    ProtocolViolation,
+    Kicked,
+    Draining,
+    /// The session the peer tried to resume is still attached to another connection.
+    SessionAttached,
+}
+
+/// Reason sent along [`CloseReason::Draining`], naming the server clients should move to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Draining {
+    pub redirect: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 #[serde(tag = "version", content = "payload")]
 pub enum Payload {
     #[serde(rename = "1")]
     V1(Version1),
 }
 
-#[derive(Debug, serde::Deserialize, serde::Serialize)]
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
 #[serde(tag = "type", content = "message")]
 pub enum Version1 {
     #[serde(rename = "ping")]
@@ -37,18 +325,264 @@ pub enum Version1 {
 
     #[serde(rename = "pong")]
     Pong,
+
+    #[serde(rename = "error")]
+    Error(RpcError),
+
+    #[serde(rename = "replication")]
+    Replication(Replication),
+
+    /// Sent by clients for the latest [`Replication`] tick they applied.
+    #[serde(rename = "acknowledge")]
+    Acknowledge(u64),
+
+    #[serde(rename = "input")]
+    Input(Input),
+}
+
+/// Snapshot of the replicated server entities, encoded against a baseline the client
+/// acknowledged.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Replication {
+    /// Server frame the snapshot was taken on, increasing by one every frame.
+    pub tick: u64,
+    /// Tick of the snapshot `entities` and `despawned` are relative to, `entities` lists
+    /// every entity when `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub baseline: Option<u64>,
+    pub entities: Vec<EntityUpdate>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub despawned: Vec<u64>,
+    /// Sequence of the latest [`Input`] of the client the snapshot reflects.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input: Option<u64>,
+}
+
+/// Input a client executed ahead of the server on a server entity it controls.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Input {
+    /// Increasing by one with every input of the client.
+    pub sequence: u64,
+    pub entity: u64,
+    /// [`crate::replication::Replicable::KIND`] of the component the input applies to.
+    pub kind: String,
+    pub input: serde_json::Value,
+}
+
+/// Components of a server entity, identified by its bits, that differ from the baseline.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct EntityUpdate {
+    pub entity: u64,
+    /// Component differences by [`crate::replication::Replicable::KIND`].
+    #[serde(default, skip_serializing_if = "std::collections::BTreeMap::is_empty")]
+    pub components: std::collections::BTreeMap<String, Delta>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+}
+
+/// Difference between two serialized values, down to the fields of objects.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum Delta {
+    /// Replaces the value.
+    #[serde(rename = "set")]
+    Set(serde_json::Value),
+
+    /// Changes the listed fields of an object, keeping the others.
+    #[serde(rename = "fields")]
+    Fields(std::collections::BTreeMap<String, Delta>),
+
+    /// Removes the field from its object.
+    #[serde(rename = "removed")]
+    Removed,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum RpcError {
+    /// The peer has no handler registered for the request.
+    #[serde(rename = "unhandled")]
+    Unhandled,
+
+    /// No response arrived before the deadline.
+    #[serde(rename = "timeout")]
+    Timeout,
+
+    /// The connection closed before a response arrived.
+    #[serde(rename = "closed")]
+    Closed,
+
+    /// The request could not be processed.
+    #[serde(rename = "failed")]
+    Failed(String),
+}
+
+impl Target {
+    #[must_use]
+    pub fn contains(&self, connection_id: usize) -> bool {
+        match self {
+            Target::Connection(id) => *id == connection_id,
+            Target::Connections(ids) => ids.contains(&connection_id),
+            Target::Broadcast => true,
+        }
+    }
+}
+
+impl<C: Channel> ChannelReceivedEvent<C> {
+    #[must_use]
+    pub fn new(connection_id: usize, payload: Payload) -> ChannelReceivedEvent<C> {
+        ChannelReceivedEvent {
+            connection_id,
+            payload,
+            channel: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<C: Channel> SendChannelEvent<C> {
+    #[must_use]
+    pub fn new(target: Target, payload: Payload) -> SendChannelEvent<C> {
+        SendChannelEvent {
+            target,
+            payload,
+            channel: std::marker::PhantomData,
+        }
+    }
+}
+
+impl Handshake {
+    pub fn deserialize(handshake: &[u8]) -> crate::Result<Handshake> {
+        serde_json::from_slice(handshake).map_err(crate::Error::decode)
+    }
+
+    pub fn serialize(&self) -> crate::Result<Vec<u8>> {
+        serde_json::to_vec(self).map_err(crate::Error::encode)
+    }
+}
+
+impl Version {
+    pub const SUPPORTED: &'static [Version] = &[Version::V1];
+
+    /// Picks the highest version supported by both peers.
+    #[must_use]
+    pub fn negotiate(local: &[Version], remote: &[Version]) -> Option<Version> {
+        local
+            .iter()
+            .filter(|version| remote.contains(version))
+            .max()
+            .copied()
+    }
+}
+
+impl CloseReason {
+    #[must_use]
+    pub fn code(self) -> quinn::VarInt {
+        quinn::VarInt::from_u32(match self {
+            CloseReason::IncompatibleVersion => 1,
+            CloseReason::ProtocolViolation => 2,
+            CloseReason::Kicked => 3,
+            CloseReason::Draining => 4,
+            CloseReason::SessionAttached => 5,
+        })
+    }
+
+    #[must_use]
+    pub fn from_code(code: quinn::VarInt) -> Option<CloseReason> {
+        match code.into_inner() {
+            1 => Some(CloseReason::IncompatibleVersion),
+            2 => Some(CloseReason::ProtocolViolation),
+            3 => Some(CloseReason::Kicked),
+            4 => Some(CloseReason::Draining),
+            5 => Some(CloseReason::SessionAttached),
+            _ => None,
+        }
+    }
+
+    #[must_use]
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::IncompatibleVersion => "incompatible version",
+            CloseReason::ProtocolViolation => "protocol violation",
+            CloseReason::Kicked => "kicked",
+            CloseReason::Draining => "server draining",
+            CloseReason::SessionAttached => "session already attached",
+        }
+    }
+}
+
+impl Draining {
+    const REDIRECT: &'static str = "; redirect=";
+
+    #[must_use]
+    pub fn encode(&self) -> Vec<u8> {
+        match &self.redirect {
+            Some(redirect) => {
+                format!(
+                    "{}{}{redirect}",
+                    CloseReason::Draining.reason(),
+                    Draining::REDIRECT
+                )
+            }
+            None => CloseReason::Draining.reason().to_string(),
+        }
+        .into_bytes()
+    }
+
+    #[must_use]
+    pub fn decode(reason: &[u8]) -> Draining {
+        let reason = String::from_utf8_lossy(reason);
+
+        Draining {
+            redirect: reason
+                .split_once(Draining::REDIRECT)
+                .map(|(_, redirect)| redirect.to_string()),
+        }
+    }
 }
 
 impl Payload {
+    /// Name of the message, used to route requests to handlers.
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Payload::V1(v1) => v1.kind(),
+        }
+    }
+
     pub fn deserialize(payload: &[u8]) -> crate::Result<Payload> {
-        Ok(serde_json::from_slice(payload)?)
+        crate::codec::Codec::deserialize(&crate::codec::Json, payload)
     }
 
     pub fn serialize(&self) -> crate::Result<Vec<u8>> {
-        Ok(serde_json::to_vec(&self)?)
+        crate::codec::Codec::serialize(&crate::codec::Json, self)
+    }
+}
+
+impl Version1 {
+    #[must_use]
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Version1::Ping => "ping",
+            Version1::Pong => "pong",
+            Version1::Error(_) => "error",
+            Version1::Replication(_) => "replication",
+            Version1::Acknowledge(_) => "acknowledge",
+            Version1::Input(_) => "input",
+        }
+    }
+}
+
+impl std::fmt::Display for RpcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RpcError::Unhandled => write!(f, "unhandled"),
+            RpcError::Timeout => write!(f, "timeout"),
+            RpcError::Closed => write!(f, "closed"),
+            RpcError::Failed(reason) => write!(f, "failed: {reason}"),
+        }
     }
 }
 
+impl std::error::Error for RpcError {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,5 +600,89 @@ mod tests {
             String::from_utf8_lossy(&result),
             "{\"version\":\"1\",\"payload\":{\"type\":\"pong\"}}"
         );
+
+        let result = Payload::V1(Version1::Error(RpcError::Unhandled))
+            .serialize()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&result),
+            "{\"version\":\"1\",\"payload\":{\"type\":\"error\",\"message\":\"unhandled\"}}"
+        );
+    }
+
+    #[test]
+    fn test_handshake() {
+        let result = Handshake::Hello {
+            versions: vec![Version::V1],
+            resume_token: None,
+        }
+        .serialize()
+        .unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&result),
+            "{\"type\":\"hello\",\"message\":{\"versions\":[\"1\"]}}"
+        );
+
+        let result = Handshake::deserialize(
+            b"{\"type\":\"hello\",\"message\":{\"versions\":[\"1\"],\"resume_token\":\"a\"}}",
+        )
+        .unwrap();
+        assert_eq!(
+            result,
+            Handshake::Hello {
+                versions: vec![Version::V1],
+                resume_token: Some("a".into()),
+            }
+        );
+
+        assert_eq!(
+            Version::negotiate(Version::SUPPORTED, &[Version::V1]),
+            Some(Version::V1)
+        );
+        assert_eq!(Version::negotiate(Version::SUPPORTED, &[]), None);
+    }
+
+    #[test]
+    fn test_close_reason() {
+        for reason in [
+            CloseReason::IncompatibleVersion,
+            CloseReason::ProtocolViolation,
+            CloseReason::Kicked,
+            CloseReason::Draining,
+            CloseReason::SessionAttached,
+        ] {
+            assert_eq!(CloseReason::from_code(reason.code()), Some(reason));
+        }
+    }
+
+    #[test]
+    fn test_draining() {
+        for draining in [
+            Draining { redirect: None },
+            Draining {
+                redirect: Some("10.0.0.2:4433".into()),
+            },
+        ] {
+            assert_eq!(Draining::decode(&draining.encode()), draining);
+        }
+
+        assert_eq!(
+            Draining {
+                redirect: Some("10.0.0.2:4433".into())
+            }
+            .encode(),
+            b"server draining; redirect=10.0.0.2:4433"
+        );
+    }
+
+    #[test]
+    fn test_target() {
+        assert!(Target::Connection(1).contains(1));
+        assert!(!Target::Connection(1).contains(2));
+
+        assert!(Target::Connections(vec![1, 2]).contains(2));
+        assert!(!Target::Connections(vec![1, 2]).contains(3));
+
+        assert!(Target::Broadcast.contains(3));
     }
 }
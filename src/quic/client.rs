@@ -1,57 +1,211 @@
 use bevy::prelude::*;
-use tokio::io::AsyncReadExt as _;
 
-use crate::{config, quic::shared};
+use crate::{
+    config,
+    quic::{shared, tls},
+};
+
+/// How long a session has to last before the reconnection backoff starts over.
+const HEALTHY_SESSION: std::time::Duration = std::time::Duration::from_secs(10);
 
 pub(crate) async fn run(
     config: config::Config,
+    conditioner: crate::conditioner::Conditioner,
     sender: tokio::sync::mpsc::UnboundedSender<crate::protocol::Event>,
 ) -> crate::Result<()> {
     let endpoint = create_endpoint(&config).await?;
 
     info!(local_addr = ?endpoint.local_addr()?, "listening");
 
-    let addr = format!("{}:{}", config.quic_server.host, config.quic_server.port).parse()?;
+    let mut addr = format!("{}:{}", config.quic_server.host, config.quic_server.port).parse()?;
+    let mut attempt = 0;
+    let mut resume_token = None;
 
     loop {
-        info!("connecting");
+        let state = match (attempt, &resume_token) {
+            (0, None) => crate::protocol::ConnectionState::Connecting,
+            _ => crate::protocol::ConnectionState::Reconnecting {
+                attempt: attempt + 1,
+            },
+        };
+        sender.send(crate::protocol::Event::ConnectionStateChanged(state))?;
+
+        info!(state = ?state, "connecting");
+
+        match connect(&endpoint, addr, &config, resume_token.clone()).await {
+            Ok((connection, version, token, format)) => {
+                resume_token = Some(token.clone());
+
+                sender.send(crate::protocol::Event::ConnectionStateChanged(
+                    crate::protocol::ConnectionState::Connected,
+                ))?;
+
+                let connected_at = std::time::Instant::now();
 
-        match endpoint.connect(addr, &config.quic_server.name)?.await {
-            Ok(connection) => {
-                if let Err(error) = shared::handle_connection(connection, sender.clone()).await {
-                    error!(error = error, "connection failed");
+                let close = match shared::handle_connection(
+                    connection,
+                    version,
+                    token,
+                    format,
+                    &conditioner,
+                    sender.clone(),
+                )
+                .await
+                {
+                    Ok(close) => close,
+                    Err(error) => {
+                        error!(error = %error, "connection failed");
+                        None
+                    }
+                };
+
+                if let Some(redirect) = close.as_ref().and_then(redirect) {
+                    info!(redirect = %redirect, "server draining, moving to redirect");
+
+                    // the session belongs to the draining server.
+                    addr = redirect;
+                    resume_token = None;
+                    continue;
                 }
+
+                if let Some(close) = close.as_ref().filter(|close| {
+                    crate::protocol::CloseReason::from_code(close.error_code)
+                        == Some(crate::protocol::CloseReason::Kicked)
+                }) {
+                    // the session is gone, resuming it would only be refused.
+                    sender.send(crate::protocol::Event::ConnectionStateChanged(
+                        crate::protocol::ConnectionState::Failed,
+                    ))?;
+
+                    return Err(crate::Error::Kicked(
+                        String::from_utf8_lossy(&close.reason).into_owned(),
+                    ));
+                }
+
+                // sessions that end right away count as failed attempts, so a server accepting
+                // and dropping connections is not hammered.
+                if connected_at.elapsed() >= HEALTHY_SESSION {
+                    attempt = 0;
+                    continue;
+                }
+            }
+            // the server will not speak any of our versions however often we ask.
+            Err(error @ crate::Error::IncompatibleVersion(_)) => {
+                sender.send(crate::protocol::Event::ConnectionStateChanged(
+                    crate::protocol::ConnectionState::Failed,
+                ))?;
+
+                return Err(error);
             }
             Err(error) => {
-                error!(error = ?error, "connection failed");
+                error!(error = %error, "connection failed");
             }
         }
+
+        attempt += 1;
+
+        let exhausted = matches!(
+            config.quic_client.backoff.max_attempts,
+            Some(max_attempts) if attempt >= max_attempts
+        );
+
+        if exhausted {
+            sender.send(crate::protocol::Event::ConnectionStateChanged(
+                crate::protocol::ConnectionState::Failed,
+            ))?;
+
+            return Err(crate::Error::AttemptsExhausted(attempt));
+        }
+
+        tokio::time::sleep(backoff(&config.quic_client.backoff, attempt)).await;
     }
 }
 
-async fn create_endpoint(c: &config::Config) -> crate::Result<quinn::Endpoint> {
-    // load server certificate
-    let mut certificate_file = tokio::fs::File::open(&c.quic_client.certificate).await?;
+/// Address a draining server asked the client to reconnect to.
+fn redirect(close: &quinn::ApplicationClose) -> Option<std::net::SocketAddr> {
+    if crate::protocol::CloseReason::from_code(close.error_code)
+        != Some(crate::protocol::CloseReason::Draining)
+    {
+        return None;
+    }
 
-    let mut certificate_contents = vec![];
-    certificate_file
-        .read_to_end(&mut certificate_contents)
-        .await?;
+    let redirect = crate::protocol::Draining::decode(&close.reason).redirect?;
+    match redirect.parse() {
+        Ok(redirect) => Some(redirect),
+        Err(error) => {
+            warn!(redirect = redirect, error = %error, "invalid redirect");
+            None
+        }
+    }
+}
 
-    let certificate = match rustls_pemfile::read_one(&mut &*certificate_contents)?.unwrap() {
-        rustls_pemfile::Item::X509Certificate(e) => rustls::Certificate(e),
-        _ => todo!(),
-    };
+/// Exponential backoff with equal jitter, so reconnecting clients spread out.
+fn backoff(backoff: &config::Backoff, attempt: u32) -> std::time::Duration {
+    let delay = backoff
+        .initial_delay
+        .saturating_mul(1 << attempt.saturating_sub(1).min(32))
+        .min(backoff.max_delay);
 
-    // create root certificate store
-    let mut roots = rustls::RootCertStore::empty();
-    roots.add(&certificate)?;
+    let mut jitter = [0; 8];
+    if ring::rand::SecureRandom::fill(&ring::rand::SystemRandom::new(), &mut jitter).is_err() {
+        return std::time::Duration::from_millis(delay);
+    }
+    let jitter = u64::from_le_bytes(jitter) % (delay / 2 + 1);
+
+    std::time::Duration::from_millis(delay - delay / 2 + jitter)
+}
+
+async fn create_endpoint(c: &config::Config) -> crate::Result<quinn::Endpoint> {
+    let verifier: std::sync::Arc<dyn rustls::client::ServerCertVerifier> = match c
+        .quic_client
+        .verification
+    {
+        // trust the authorities that may have issued the server certificate
+        config::Verification::Authority => {
+            std::sync::Arc::new(rustls::client::WebPkiVerifier::new(
+                tls::read_roots(&certificate_authorities(&c.quic_client)).await?,
+                None,
+            ))
+        }
+        config::Verification::Pinned => std::sync::Arc::new(tls::PinnedServerVerifier::new(
+            &c.quic_client.pinned_fingerprints,
+        )?),
+        config::Verification::Insecure => {
+            warn!("server certificates are not verified, never use insecure verification in production");
+            std::sync::Arc::new(tls::InsecureServerVerifier)
+        }
+    };
 
-    // create config
     let crypto = rustls::ClientConfig::builder()
         .with_safe_defaults()
-        .with_root_certificates(roots)
-        .with_no_client_auth();
+        .with_custom_certificate_verifier(verifier);
+
+    // present a client certificate chain when one is configured, only sent when the server
+    // asks for one
+    let mut crypto = match (
+        &c.quic_client.client_certificate,
+        &c.quic_client.client_private_key,
+    ) {
+        (Some(certificate), Some(private_key)) => {
+            let certificates = tls::read_certificates(certificate).await?;
+            let private_key = tls::read_private_key(private_key).await?;
+            crypto.with_single_cert(certificates, private_key)?
+        }
+        (Some(certificate), None) => {
+            return Err(crate::Error::certificate(
+                certificate,
+                "client certificate configured without a private key",
+            ))
+        }
+        (None, Some(private_key)) => {
+            return Err(crate::Error::certificate(
+                private_key,
+                "client private key configured without a certificate",
+            ))
+        }
+        (None, None) => crypto.with_no_client_auth(),
+    };
+    crypto.alpn_protocols = c.quic_client.codec.alpn_protocols();
 
     let config = quinn::ClientConfig::new(std::sync::Arc::new(crypto));
 
@@ -62,3 +216,151 @@ async fn create_endpoint(c: &config::Config) -> crate::Result<quinn::Endpoint> {
 
     Ok(endpoint)
 }
+
+/// Every authority trusted to issue the server certificate.
+fn certificate_authorities(config: &config::QuicClient) -> Vec<String> {
+    config
+        .certificate_authorities
+        .iter()
+        .chain(&config.certificate)
+        .cloned()
+        .collect()
+}
+
+async fn connect(
+    endpoint: &quinn::Endpoint,
+    addr: std::net::SocketAddr,
+    config: &config::Config,
+    resume_token: Option<String>,
+) -> crate::Result<(
+    quinn::NewConnection,
+    crate::protocol::Version,
+    String,
+    crate::codec::Format,
+)> {
+    let connection = endpoint.connect(addr, &config.quic_server.name)?.await?;
+
+    let format = shared::negotiated_format(&connection.connection)?;
+
+    match tokio::time::timeout(
+        shared::HANDSHAKE_TIMEOUT,
+        handshake(&connection.connection, resume_token),
+    )
+    .await
+    {
+        Ok(result) => {
+            let (version, resume_token) = result?;
+            Ok((connection, version, resume_token, format))
+        }
+        Err(_) => {
+            shared::close(
+                &connection.connection,
+                crate::protocol::CloseReason::ProtocolViolation,
+            );
+            Err(crate::Error::Timeout("handshake"))
+        }
+    }
+}
+
+async fn handshake(
+    connection: &quinn::Connection,
+    resume_token: Option<String>,
+) -> crate::Result<(crate::protocol::Version, String)> {
+    let (mut send, recv) = connection.open_bi().await?;
+
+    let request = crate::protocol::Handshake::Hello {
+        versions: crate::protocol::Version::SUPPORTED.to_vec(),
+        resume_token,
+    };
+    send.write_all(&request.serialize()?).await?;
+    send.finish().await?;
+
+    let response = crate::protocol::Handshake::deserialize(
+        &recv.read_to_end(crate::protocol::MAX_FRAME_SIZE).await?,
+    )?;
+
+    match response {
+        crate::protocol::Handshake::Accept {
+            version,
+            resume_token,
+        } if crate::protocol::Version::SUPPORTED.contains(&version) => Ok((version, resume_token)),
+        crate::protocol::Handshake::Reject { versions } => {
+            Err(crate::Error::IncompatibleVersion(versions))
+        }
+        response => {
+            shared::close(connection, crate::protocol::CloseReason::ProtocolViolation);
+            Err(crate::Error::Protocol(format!(
+                "unexpected handshake {response:?}"
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let config = config::Backoff {
+            initial_delay: 100,
+            max_delay: 1000,
+            max_attempts: None,
+        };
+
+        for (attempt, delay) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (40, 1000),
+        ] {
+            let result = backoff(&config, attempt).as_millis();
+            assert!(
+                (delay / 2..=delay).contains(&result),
+                "attempt {attempt} waited {result}ms"
+            );
+        }
+    }
+
+    #[test]
+    fn test_redirect() {
+        let close = |reason: crate::protocol::CloseReason, redirect: Option<&str>| {
+            quinn::ApplicationClose {
+                error_code: reason.code(),
+                reason: crate::protocol::Draining {
+                    redirect: redirect.map(Into::into),
+                }
+                .encode()
+                .into(),
+            }
+        };
+
+        assert_eq!(
+            redirect(&close(
+                crate::protocol::CloseReason::Draining,
+                Some("127.0.0.1:4434")
+            )),
+            Some(([127, 0, 0, 1], 4434).into())
+        );
+        assert_eq!(
+            redirect(&close(crate::protocol::CloseReason::Draining, None)),
+            None
+        );
+        assert_eq!(
+            redirect(&close(
+                crate::protocol::CloseReason::Draining,
+                Some("elsewhere")
+            )),
+            None
+        );
+        assert_eq!(
+            redirect(&close(
+                crate::protocol::CloseReason::Kicked,
+                Some("127.0.0.1:4434")
+            )),
+            None
+        );
+    }
+}
@@ -1,6 +1,10 @@
 #[cfg(feature = "client")]
 pub(crate) mod client;
 #[cfg(feature = "server")]
+mod reload;
+#[cfg(feature = "server")]
 pub(crate) mod server;
 #[cfg(any(feature = "client", feature = "server"))]
 mod shared;
+#[cfg(any(feature = "client", feature = "server"))]
+mod tls;
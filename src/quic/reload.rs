@@ -0,0 +1,161 @@
+use bevy::prelude::*;
+
+use crate::config;
+
+/// Decides when the server reads its TLS files again: when one of them changes on disk or
+/// the process receives SIGHUP.
+pub(super) struct Trigger {
+    paths: Vec<String>,
+    modified: Vec<Option<std::time::SystemTime>>,
+    /// `None` when the files are not polled.
+    interval: Option<tokio::time::Interval>,
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl Trigger {
+    pub(super) async fn new(config: &config::QuicServer) -> crate::Result<Trigger> {
+        let paths: Vec<String> = [
+            Some(&config.certificate),
+            Some(&config.private_key),
+            config.certificate_authority.as_ref(),
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect();
+
+        let interval = (config.reload_interval > 0).then(|| {
+            let mut interval =
+                tokio::time::interval(std::time::Duration::from_millis(config.reload_interval));
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval
+        });
+
+        Ok(Trigger {
+            modified: modified(&paths).await,
+            paths,
+            interval,
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?,
+        })
+    }
+
+    pub(super) async fn triggered(&mut self) {
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tick(&mut self.interval) => if self.poll().await {
+                    return;
+                },
+                Some(()) = self.hangup.recv() => {
+                    info!("reload requested");
+                    return;
+                }
+            }
+
+            #[cfg(not(unix))]
+            {
+                tick(&mut self.interval).await;
+                if self.poll().await {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns true when a file was modified, created or removed since the last poll.
+    async fn poll(&mut self) -> bool {
+        let modified = modified(&self.paths).await;
+        if modified == self.modified {
+            return false;
+        }
+
+        info!(paths = ?self.paths, "certificate files changed");
+        self.modified = modified;
+        true
+    }
+}
+
+/// Waits for the next poll, forever when the files are not polled.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn modified(paths: &[String]) -> Vec<Option<std::time::SystemTime>> {
+    let mut modified = Vec::with_capacity(paths.len());
+    for path in paths {
+        modified.push(
+            tokio::fs::metadata(path)
+                .await
+                .and_then(|metadata| metadata.modified())
+                .ok(),
+        );
+    }
+    modified
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_poll() {
+        let path = std::env::temp_dir().join(format!("reload-{}.crt", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let mut trigger = Trigger::new(&config::QuicServer {
+            certificate_authority: None,
+            codec: crate::codec::Format::Json,
+            host: "127.0.0.1".into(),
+            port: 0,
+            certificate: path.clone(),
+            private_key: path.clone(),
+            name: "localhost".into(),
+            reload_interval: 1000,
+        })
+        .await
+        .unwrap();
+
+        assert!(!trigger.poll().await);
+
+        tokio::fs::write(&path, b"certificate").await.unwrap();
+        assert!(trigger.poll().await);
+        assert!(!trigger.poll().await);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert!(trigger.poll().await);
+    }
+
+    #[tokio::test]
+    async fn test_no_polling() {
+        let path = std::env::temp_dir().join(format!("reload-{}.key", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        let mut trigger = Trigger::new(&config::QuicServer {
+            certificate_authority: None,
+            codec: crate::codec::Format::Json,
+            host: "127.0.0.1".into(),
+            port: 0,
+            certificate: path.clone(),
+            private_key: path.clone(),
+            name: "localhost".into(),
+            reload_interval: 0,
+        })
+        .await
+        .unwrap();
+
+        // changes are left for SIGHUP to pick up.
+        tokio::fs::write(&path, b"private key").await.unwrap();
+        let triggered =
+            tokio::time::timeout(std::time::Duration::from_millis(100), trigger.triggered()).await;
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert!(triggered.is_err());
+    }
+}
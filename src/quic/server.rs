@@ -1,66 +1,107 @@
 use bevy::prelude::*;
 use futures::StreamExt as _;
-use tokio::io::AsyncReadExt as _;
 
-use crate::{config, quic::shared};
+use crate::{
+    config,
+    quic::{reload, shared, tls},
+};
 
 pub(crate) async fn run(
     config: config::Config,
+    health: crate::health::Health,
+    mut shutdown: tokio::sync::watch::Receiver<crate::shutdown::Phase>,
+    conditioner: crate::conditioner::Conditioner,
     sender: tokio::sync::mpsc::UnboundedSender<crate::protocol::Event>,
 ) -> crate::Result<()> {
     let (endpoint, mut incoming) = create_endpoint(&config).await?;
 
     info!(local_addr = ?endpoint.local_addr()?, "listening");
+    health.set_listening(true);
+
+    let mut reload = reload::Trigger::new(&config.quic_server).await?;
+    let resume_tokens = std::sync::Arc::new(ResumeTokens::new()?);
+
+    loop {
+        let connection = tokio::select! {
+            connection = incoming.next() => match connection {
+                Some(connection) => connection,
+                None => break,
+            },
+            _ = draining(&mut shutdown) => {
+                drain(&endpoint, &config.shutdown).await;
+                break;
+            }
+            _ = reload.triggered() => {
+                // existing connections keep the configuration they were accepted with.
+                match server_config(&config).await {
+                    Ok(server_config) => {
+                        endpoint.set_server_config(Some(server_config));
+                        info!("certificate reloaded");
+                    }
+                    Err(error) => {
+                        error!(error = %error, "certificate reload rejected");
+                    }
+                }
+                continue;
+            }
+        };
 
-    while let Some(connection) = incoming.next().await {
         info!("connection incoming");
 
+        let conditioner = conditioner.clone();
+        let resume_tokens = resume_tokens.clone();
         let sender = sender.clone();
 
         tokio::spawn(async move {
-            if let Err(error) = handle_connection(connection, sender).await {
-                error!(error = error, "connection failed");
+            if let Err(error) =
+                handle_connection(connection, conditioner, &resume_tokens, sender).await
+            {
+                error!(error = %error, "connection failed");
             }
         });
     }
 
+    health.set_listening(false);
+
     Ok(())
 }
 
-async fn create_endpoint(c: &config::Config) -> crate::Result<(quinn::Endpoint, quinn::Incoming)> {
-    // load server certificate
-    let mut certificate_file = tokio::fs::File::open(&c.quic_server.certificate).await?;
-    let mut private_key_file = tokio::fs::File::open(&c.quic_server.private_key).await?;
-
-    let mut certificate_contents = vec![];
-    certificate_file
-        .read_to_end(&mut certificate_contents)
-        .await?;
-
-    let mut private_key_contents = vec![];
-    private_key_file
-        .read_to_end(&mut private_key_contents)
-        .await?;
-
-    let certificate = match rustls_pemfile::read_one(&mut &*certificate_contents)?.unwrap() {
-        rustls_pemfile::Item::X509Certificate(e) => rustls::Certificate(e),
-        _ => todo!(),
-    };
-    let private_key = match rustls_pemfile::read_one(&mut &*private_key_contents)?.unwrap() {
-        rustls_pemfile::Item::RSAKey(e)
-        | rustls_pemfile::Item::PKCS8Key(e)
-        | rustls_pemfile::Item::ECKey(e) => rustls::PrivateKey(e),
-        _ => todo!(),
+async fn draining(shutdown: &mut tokio::sync::watch::Receiver<crate::shutdown::Phase>) {
+    while *shutdown.borrow() == crate::shutdown::Phase::Running {
+        if shutdown.changed().await.is_err() {
+            // without a shutdown sequence the server runs until the process ends.
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Stops accepting connections and asks connected clients to move elsewhere, waiting at most
+/// the grace period for them to be told.
+async fn drain(endpoint: &quinn::Endpoint, config: &config::Shutdown) {
+    info!(redirect = ?config.redirect, "closing connections");
+
+    endpoint.set_server_config(None);
+
+    let draining = crate::protocol::Draining {
+        redirect: config.redirect.clone(),
     };
+    endpoint.close(
+        crate::protocol::CloseReason::Draining.code(),
+        &draining.encode(),
+    );
 
-    // create config
-    let crypto = rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(vec![certificate], private_key)?;
+    // the close frames carrying the redirect are only sent while the endpoint lives.
+    let grace_period = std::time::Duration::from_millis(config.grace_period);
+    if tokio::time::timeout(grace_period, endpoint.wait_idle())
+        .await
+        .is_err()
+    {
+        warn!("connections still closing after the grace period");
+    }
+}
 
-    let mut config = quinn::ServerConfig::with_crypto(std::sync::Arc::new(crypto));
-    config.use_retry(true);
+async fn create_endpoint(c: &config::Config) -> crate::Result<(quinn::Endpoint, quinn::Incoming)> {
+    let config = server_config(c).await?;
 
     // create endpoint
     let addr = format!("{}:{}", c.quic_server.host, c.quic_server.port).parse()?;
@@ -69,9 +110,211 @@ async fn create_endpoint(c: &config::Config) -> crate::Result<(quinn::Endpoint,
     Ok((endpoint, incoming))
 }
 
+async fn server_config(c: &config::Config) -> crate::Result<quinn::ServerConfig> {
+    // load server certificate chain, the leaf must match the private key
+    let certificates = tls::read_certificates(&c.quic_server.certificate).await?;
+    let private_key = tls::read_private_key(&c.quic_server.private_key).await?;
+    tls::verify_key_pair(&c.quic_server.private_key, &certificates[0], &private_key)?;
+
+    // require client certificates issued by the authority when one is configured
+    let client_cert_verifier = match &c.quic_server.certificate_authority {
+        Some(certificate_authority) => rustls::server::AllowAnyAuthenticatedClient::new(
+            tls::read_roots(std::slice::from_ref(certificate_authority)).await?,
+        ),
+        None => rustls::server::NoClientAuth::new(),
+    };
+
+    // create config
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_cert_verifier)
+        .with_single_cert(certificates, private_key)?;
+    crypto.alpn_protocols = c.quic_server.codec.alpn_protocols();
+
+    let mut config = quinn::ServerConfig::with_crypto(std::sync::Arc::new(crypto));
+    config.use_retry(true);
+
+    Ok(config)
+}
+
 async fn handle_connection(
     connection: quinn::Connecting,
+    conditioner: crate::conditioner::Conditioner,
+    resume_tokens: &ResumeTokens,
     sender: tokio::sync::mpsc::UnboundedSender<crate::protocol::Event>,
 ) -> crate::Result<()> {
-    shared::handle_connection(connection.await?, sender).await
+    let mut connection = connection.await?;
+
+    let format = shared::negotiated_format(&connection.connection)?;
+
+    let (version, resume_token) = match tokio::time::timeout(
+        shared::HANDSHAKE_TIMEOUT,
+        handshake(
+            &connection.connection,
+            &mut connection.bi_streams,
+            resume_tokens,
+        ),
+    )
+    .await
+    {
+        Ok(version) => version?,
+        Err(_) => {
+            shared::close(
+                &connection.connection,
+                crate::protocol::CloseReason::ProtocolViolation,
+            );
+            return Err(crate::Error::Timeout("handshake"));
+        }
+    };
+
+    shared::handle_connection(
+        connection,
+        version,
+        resume_token,
+        format,
+        &conditioner,
+        sender,
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn handshake(
+    connection: &quinn::Connection,
+    bi_streams: &mut quinn::IncomingBiStreams,
+    resume_tokens: &ResumeTokens,
+) -> crate::Result<(crate::protocol::Version, String)> {
+    let (mut send, recv) = match bi_streams.next().await {
+        Some(stream) => stream?,
+        None => {
+            return Err(crate::Error::Protocol(
+                "connection closed during handshake".into(),
+            ))
+        }
+    };
+
+    let request = crate::protocol::Handshake::deserialize(
+        &recv.read_to_end(crate::protocol::MAX_FRAME_SIZE).await?,
+    )?;
+
+    let (versions, resume_token) = match request {
+        crate::protocol::Handshake::Hello {
+            versions,
+            resume_token,
+        } => (versions, resume_token),
+        request => {
+            shared::close(connection, crate::protocol::CloseReason::ProtocolViolation);
+            return Err(crate::Error::Protocol(format!(
+                "unexpected handshake {request:?}"
+            )));
+        }
+    };
+
+    match crate::protocol::Version::negotiate(crate::protocol::Version::SUPPORTED, &versions) {
+        Some(version) => {
+            // the network plugin only honours a token while its session is detached.
+            let resume_token = match resume_token {
+                Some(resume_token) if resume_tokens.verify(&resume_token) => resume_token,
+                _ => resume_tokens.issue()?,
+            };
+
+            let response = crate::protocol::Handshake::Accept {
+                version,
+                resume_token: resume_token.clone(),
+            };
+            send.write_all(&response.serialize()?).await?;
+            send.finish().await?;
+
+            Ok((version, resume_token))
+        }
+        None => {
+            let response = crate::protocol::Handshake::Reject {
+                versions: crate::protocol::Version::SUPPORTED.to_vec(),
+            };
+            send.write_all(&response.serialize()?).await?;
+            send.finish().await?;
+
+            shared::close(
+                connection,
+                crate::protocol::CloseReason::IncompatibleVersion,
+            );
+            Err(crate::Error::IncompatibleVersion(versions))
+        }
+    }
+}
+
+/// Issues resume tokens signed with a per-process secret, so clients can only present tokens
+/// this server handed out.
+struct ResumeTokens {
+    key: ring::hmac::Key,
+    rng: ring::rand::SystemRandom,
+}
+
+impl ResumeTokens {
+    fn new() -> crate::Result<Self> {
+        let rng = ring::rand::SystemRandom::new();
+        let key = ring::hmac::Key::generate(ring::hmac::HMAC_SHA256, &rng)
+            .map_err(|_| crate::Error::Random("resume token secret"))?;
+
+        Ok(Self { key, rng })
+    }
+
+    /// Random nonce followed by its signature, hex encoded.
+    fn issue(&self) -> crate::Result<String> {
+        let mut nonce = [0; 16];
+        ring::rand::SecureRandom::fill(&self.rng, &mut nonce)
+            .map_err(|_| crate::Error::Random("resume token"))?;
+
+        let tag = ring::hmac::sign(&self.key, &nonce);
+
+        Ok(nonce
+            .iter()
+            .chain(tag.as_ref())
+            .map(|byte| format!("{byte:02x}"))
+            .collect())
+    }
+
+    fn verify(&self, resume_token: &str) -> bool {
+        let bytes = match decode_hex(resume_token) {
+            Some(bytes) if bytes.len() > 16 => bytes,
+            _ => return false,
+        };
+        let (nonce, tag) = bytes.split_at(16);
+
+        ring::hmac::verify(&self.key, nonce, tag).is_ok()
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .filter(|pair| pair.len() == 2)
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resume_tokens() {
+        let resume_tokens = ResumeTokens::new().unwrap();
+        let resume_token = resume_tokens.issue().unwrap();
+
+        assert!(resume_tokens.verify(&resume_token));
+        assert_ne!(resume_tokens.issue().unwrap(), resume_token);
+
+        // tokens chosen by the client or issued by another server are refused.
+        assert!(!resume_tokens.verify(&"0".repeat(32)));
+        assert!(!resume_tokens.verify(&resume_token[..32]));
+        assert!(!resume_tokens.verify(&format!("{}00", &resume_token[..resume_token.len() - 2])));
+        assert!(!ResumeTokens::new().unwrap().verify(&resume_token));
+        assert!(!resume_tokens.verify("résumé"));
+    }
 }
@@ -1,27 +1,105 @@
 use bevy::prelude::*;
 use futures::StreamExt as _;
 
-use crate::protocol;
+use crate::{
+    codec::{self, Codec},
+    conditioner,
+    metrics::metrics,
+    protocol,
+    quic::tls,
+    transport::{self, Session},
+};
+
+pub(super) const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How long an incoming request waits for the network plugin to respond.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often the connection statistics are sampled and reported to the network plugin.
+const STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Codec selected through ALPN, connections that did not negotiate one are refused.
+pub(super) fn negotiated_format(connection: &quinn::Connection) -> crate::Result<codec::Format> {
+    let protocol = connection
+        .handshake_data()
+        .and_then(|x| x.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|x| x.protocol);
+
+    match protocol.as_deref().and_then(codec::Format::from_alpn) {
+        Some(format) => Ok(format),
+        None => {
+            close(connection, protocol::CloseReason::ProtocolViolation);
+            Err(crate::Error::Protocol(format!(
+                "unknown application protocol {:?}",
+                protocol.map(|x| String::from_utf8_lossy(&x).into_owned())
+            )))
+        }
+    }
+}
+
+/// Counters of a single connection, shared by the tasks serving it.
+#[derive(Debug, Default)]
+pub(super) struct Counters {
+    messages_sent: std::sync::atomic::AtomicU64,
+    messages_received: std::sync::atomic::AtomicU64,
+    /// Round trip of the latest keep-alive ping in microseconds, zero until one is answered.
+    ping_rtt: std::sync::atomic::AtomicU64,
+}
+
+impl Counters {
+    fn sent(&self) {
+        self.messages_sent
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    fn received(&self) {
+        self.messages_received
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// Codec of a connection that counts the payloads it encodes and decodes, used where only
+/// network plugin payloads pass.
+#[derive(Debug)]
+struct CountingCodec {
+    codec: std::sync::Arc<dyn Codec>,
+    counters: std::sync::Arc<Counters>,
+}
+
+impl Codec for CountingCodec {
+    fn serialize(&self, payload: &protocol::Payload) -> crate::Result<Vec<u8>> {
+        let bytes = self.codec.serialize(payload)?;
+        self.counters.sent();
+        Ok(bytes)
+    }
+
+    fn deserialize(&self, bytes: &[u8]) -> crate::Result<protocol::Payload> {
+        let payload = self.codec.deserialize(bytes)?;
+        self.counters.received();
+        Ok(payload)
+    }
+}
 
 pub(super) async fn handle_connection(
     connection: quinn::NewConnection,
+    version: protocol::Version,
+    resume_token: String,
+    format: codec::Format,
+    conditioner: &conditioner::Conditioner,
     sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
-) -> crate::Result<()> {
+) -> crate::Result<Option<quinn::ApplicationClose>> {
+    let counters = std::sync::Arc::new(Counters::default());
+    let codec: std::sync::Arc<dyn Codec> = std::sync::Arc::new(CountingCodec {
+        codec: format.codec(),
+        counters: counters.clone(),
+    });
+
     let span = info_span!(
         "connection",
         remote_address = ?connection.connection.remote_address(),
+        codec = ?format,
         connection_id = connection.connection.stable_id(),
-        protocol = connection
-            .connection
-            .handshake_data()
-            .unwrap()
-            .downcast::<quinn::crypto::rustls::HandshakeData>()
-            .unwrap()
-            .protocol
-            .map_or_else(
-                || "<none>".into(),
-                |x| String::from_utf8_lossy(&x).into_owned()
-            )
+        version = ?version
     );
     let _guard = span.enter();
 
@@ -31,52 +109,116 @@ pub(super) async fn handle_connection(
         connection,
         uni_streams,
         bi_streams,
+        datagrams,
         ..
     } = connection;
 
+    if connection.max_datagram_size().is_none() {
+        info!("datagrams unsupported by peer, falling back to streams");
+    }
+
+    let peer_identity = tls::peer_identity(&connection);
+    if let Some(peer_identity) = &peer_identity {
+        info!(subject = %peer_identity.subject, "peer authenticated");
+    }
+
+    // the network plugin sends to the conditioner, which forwards to the outgoing stream.
     let (s, r) = tokio::sync::mpsc::unbounded_channel();
+    let (conditioned_s, conditioned_r) = tokio::sync::mpsc::unbounded_channel();
+    let session = Session::new(connection.stable_id(), sender.clone());
 
     sender.send(protocol::Event::ConnectionCreated(
         protocol::ConnectionCreatedEvent {
             connection_id: connection.stable_id(),
+            version,
+            resume_token,
+            peer_identity,
+            remote_address: connection.remote_address(),
+            codec: format,
             sender: s,
         },
     ))?;
+    metrics().quic_connections.inc();
 
     let result = tokio::select! {
-        result = handle_incoming_bi_streams(connection.clone(), sender.clone(), bi_streams) => result,
-        result = handle_incoming_uni_streams(connection.clone(), sender.clone(), uni_streams) => result,
-        result = handle_outgoing_keep_alive(connection.clone()) => result,
-        result = handle_outgoing_stream(connection.clone(), r) => result,
+        result = handle_incoming_bi_streams(format.codec(), counters.clone(), session.clone(), bi_streams) => result,
+        result = handle_incoming_uni_streams(connection.clone(), codec.clone(), session.clone(), uni_streams) => result,
+        result = handle_incoming_datagrams(codec.clone(), session.clone(), datagrams) => result,
+        result = handle_outgoing_keep_alive(connection.clone(), format.codec(), counters.clone()) => result,
+        result = handle_stats(connection.clone(), counters.clone(), sender.clone()) => result,
+        result = handle_conditioner(conditioner.subscribe(), format.codec(), session.clone(), r, conditioned_s) => result,
+        result = handle_outgoing_stream(connection.clone(), codec.clone(), session.clone(), conditioned_r) => result,
     };
 
-    sender.send(protocol::Event::ConnectionDestroyed(
-        protocol::ConnectionDestroyedEvent {
-            connection_id: connection.stable_id(),
+    metrics().quic_connections.dec();
+
+    conditioner.forget(session.connection_id());
+    session.destroyed()?;
+
+    match result {
+        Ok(()) => Ok(None),
+        Err(error) => match application_close(&error) {
+            Some(close) => {
+                info!(code = %close.error_code, "connection closed");
+                Ok(Some(close.clone()))
+            }
+            None => Err(error),
         },
-    ))?;
+    }
+}
+
+/// Close the peer sent, whichever operation noticed it first.
+fn application_close(error: &crate::Error) -> Option<&quinn::ApplicationClose> {
+    let error = match error {
+        crate::Error::Transport(error) => error,
+        _ => return None,
+    };
 
-    result
+    let error = match error {
+        crate::TransportError::Connection(error)
+        | crate::TransportError::Write(quinn::WriteError::ConnectionLost(error))
+        | crate::TransportError::Read(quinn::ReadError::ConnectionLost(error))
+        | crate::TransportError::ReadExact(quinn::ReadExactError::ReadError(
+            quinn::ReadError::ConnectionLost(error),
+        ))
+        | crate::TransportError::ReadToEnd(quinn::ReadToEndError::Read(
+            quinn::ReadError::ConnectionLost(error),
+        ))
+        | crate::TransportError::SendDatagram(quinn::SendDatagramError::ConnectionLost(error)) => {
+            error
+        }
+        _ => return None,
+    };
+
+    match error {
+        quinn::ConnectionError::ApplicationClosed(close) => Some(close),
+        _ => None,
+    }
+}
+
+pub(super) fn close(connection: &quinn::Connection, reason: protocol::CloseReason) {
+    warn!(reason = reason.reason(), "closing connection");
+
+    connection.close(reason.code(), reason.reason().as_bytes());
 }
 
 pub(super) async fn handle_incoming_bi_streams(
-    connection: quinn::Connection,
-    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    codec: std::sync::Arc<dyn Codec>,
+    counters: std::sync::Arc<Counters>,
+    session: Session,
     mut bi_streams: quinn::IncomingBiStreams,
 ) -> crate::Result<()> {
     while let Some(stream) = bi_streams.next().await {
         let (send, recv) = match stream {
             Ok(stream) => stream,
-            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                info!("connection closed");
-                return Ok(());
-            }
             Err(error) => return Err(error.into()),
         };
+        stream_opened("incoming", "bi");
 
-        tokio::spawn(handle_incoming_bi_request(
-            connection.clone(),
-            sender.clone(),
+        spawn_stream(handle_incoming_bi_request(
+            codec.clone(),
+            counters.clone(),
+            session.clone(),
             recv,
             send,
         ));
@@ -87,22 +229,21 @@ pub(super) async fn handle_incoming_bi_streams(
 
 pub(super) async fn handle_incoming_uni_streams(
     connection: quinn::Connection,
-    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    codec: std::sync::Arc<dyn Codec>,
+    session: Session,
     mut uni_streams: quinn::IncomingUniStreams,
 ) -> crate::Result<()> {
     while let Some(stream) = uni_streams.next().await {
         let recv = match stream {
             Ok(stream) => stream,
-            Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                info!("connection closed");
-                return Ok(());
-            }
             Err(error) => return Err(error.into()),
         };
+        stream_opened("incoming", "uni");
 
-        tokio::spawn(handle_incoming_uni_request(
+        spawn_stream(handle_incoming_uni_request(
             connection.clone(),
-            sender.clone(),
+            codec.clone(),
+            session.clone(),
             recv,
         ));
     }
@@ -110,79 +251,495 @@ pub(super) async fn handle_incoming_uni_streams(
     Ok(())
 }
 
+pub(super) async fn handle_incoming_datagrams(
+    codec: std::sync::Arc<dyn Codec>,
+    session: Session,
+    mut datagrams: quinn::Datagrams,
+) -> crate::Result<()> {
+    while let Some(datagram) = datagrams.next().await {
+        let datagram = match datagram {
+            Ok(datagram) => datagram,
+            Err(error) => return Err(error.into()),
+        };
+
+        // a corrupt datagram is dropped like a lost one rather than failing the connection.
+        let (channel, payload) = match datagram.split_first() {
+            Some((&channel, bytes)) => match decode(&*codec, bytes) {
+                Ok(payload) => (channel, payload),
+                Err(error) => {
+                    warn!(error = %error, "datagram dropped");
+                    continue;
+                }
+            },
+            None => {
+                warn!("empty datagram dropped");
+                continue;
+            }
+        };
+
+        session.message(channel, payload)?;
+    }
+
+    Ok(())
+}
+
+/// Answers keep-alive pings and requests of the network plugin, only the latter are counted.
 pub(super) async fn handle_incoming_bi_request(
-    connection: quinn::Connection,
-    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    codec: std::sync::Arc<dyn Codec>,
+    counters: std::sync::Arc<Counters>,
+    session: Session,
     recv: quinn::RecvStream,
     mut send: quinn::SendStream,
 ) -> crate::Result<()> {
-    let request_bytes = recv.read_to_end(64 * 1024).await?;
-    let request = protocol::Payload::deserialize(&request_bytes)?;
-
-    match &request {
-        protocol::Payload::V1(v1) => match v1 {
-            protocol::Version1::Ping => {
-                let response = protocol::Payload::V1(protocol::Version1::Pong);
-                send.write_all(&response.serialize()?).await?;
-            }
-            protocol::Version1::Pong => todo!(),
-        },
+    let request_bytes = recv.read_to_end(protocol::MAX_FRAME_SIZE).await?;
+    let request = decode(&*codec, &request_bytes)?;
+
+    let response = match session.request(request)? {
+        transport::Answer::Now(response) => Ok(response),
+        transport::Answer::Later {
+            request_id,
+            receiver,
+        } => {
+            counters.received();
+
+            let response = match tokio::time::timeout(REQUEST_TIMEOUT, receiver).await {
+                Ok(Ok(response)) => response,
+                Ok(Err(_)) => Err(protocol::RpcError::Closed),
+                Err(_) => {
+                    session.forget(request_id);
+                    Err(protocol::RpcError::Timeout)
+                }
+            };
+
+            counters.sent();
+            response
+        }
     };
 
-    send.finish().await?;
+    let response =
+        response.unwrap_or_else(|error| protocol::Payload::V1(protocol::Version1::Error(error)));
 
-    sender.send(protocol::Event::PayloadReceived(
-        protocol::PayloadReceivedEvent {
-            connection_id: connection.stable_id(),
-            payload: request,
-        },
-    ))?;
+    send.write_all(&encode(&*codec, &response)?).await?;
+    send.finish().await?;
 
     Ok(())
 }
 
+/// Reads a stream made of a channel id followed by length prefixed payloads.
 pub(super) async fn handle_incoming_uni_request(
     connection: quinn::Connection,
-    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
-    recv: quinn::RecvStream,
+    codec: std::sync::Arc<dyn Codec>,
+    session: Session,
+    mut recv: quinn::RecvStream,
 ) -> crate::Result<()> {
-    let payload = recv.read_to_end(64 * 1024).await?;
-    let request = protocol::Payload::deserialize(&payload)?;
+    let mut channel = [0; 1];
+    recv.read_exact(&mut channel).await?;
+    let [channel] = channel;
 
-    sender.send(protocol::Event::PayloadReceived(
-        protocol::PayloadReceivedEvent {
-            connection_id: connection.stable_id(),
-            payload: request,
-        },
-    ))?;
+    loop {
+        let mut length = [0; 4];
+        match recv.read_exact(&mut length).await {
+            Ok(()) => {}
+            Err(quinn::ReadExactError::FinishedEarly) => return Ok(()),
+            Err(error) => return Err(error.into()),
+        }
 
-    Ok(())
+        let length = u32::from_be_bytes(length) as usize;
+        if length > protocol::MAX_FRAME_SIZE {
+            // skipping the frame would mean reading it anyway, the peer is misbehaving.
+            close(&connection, protocol::CloseReason::ProtocolViolation);
+            return Err(crate::Error::Protocol(format!(
+                "frame of {length} bytes exceeds limit on channel {channel}"
+            )));
+        }
+
+        let mut bytes = vec![0; length];
+        recv.read_exact(&mut bytes).await?;
+
+        // the next frame still starts after this one, so the stream carries on.
+        let payload = match decode(&*codec, &bytes) {
+            Ok(payload) => payload,
+            Err(error) => {
+                warn!(channel = channel, error = %error, "frame dropped");
+                continue;
+            }
+        };
+
+        session.message(channel, payload)?;
+    }
 }
 
-pub(super) async fn handle_outgoing_keep_alive(connection: quinn::Connection) -> crate::Result<()> {
+async fn handle_outgoing_keep_alive(
+    connection: quinn::Connection,
+    codec: std::sync::Arc<dyn Codec>,
+    counters: std::sync::Arc<Counters>,
+) -> crate::Result<()> {
     loop {
+        let started = std::time::Instant::now();
+
         let (mut send, recv) = connection.open_bi().await?;
+        stream_opened("outgoing", "bi");
 
         let request = protocol::Payload::V1(protocol::Version1::Ping);
-        send.write_all(&request.serialize()?).await?;
+        send.write_all(&encode(&*codec, &request)?).await?;
         send.finish().await?;
 
-        let _response = protocol::Payload::deserialize(&recv.read_to_end(64 * 1024).await?)?;
+        let _response = decode(&*codec, &recv.read_to_end(protocol::MAX_FRAME_SIZE).await?)?;
+
+        counters.ping_rtt.store(
+            u64::try_from(started.elapsed().as_micros())
+                .unwrap_or(u64::MAX)
+                .max(1),
+            std::sync::atomic::Ordering::Relaxed,
+        );
 
         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
     }
 }
 
+/// Samples the connection statistics, independently of the keep-alive so a peer that never
+/// answers pings is still measured.
+async fn handle_stats(
+    connection: quinn::Connection,
+    counters: std::sync::Arc<Counters>,
+    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+) -> crate::Result<()> {
+    let mut interval = tokio::time::interval(STATS_INTERVAL);
+    let mut previous = connection.stats();
+
+    loop {
+        interval.tick().await;
+
+        // quinn only exposes cumulative statistics, so metrics are fed the difference.
+        let stats = connection.stats();
+        metrics()
+            .quic_bytes
+            .with_label_values(&["sent"])
+            .inc_by(stats.udp_tx.bytes - previous.udp_tx.bytes);
+        metrics()
+            .quic_bytes
+            .with_label_values(&["received"])
+            .inc_by(stats.udp_rx.bytes - previous.udp_rx.bytes);
+        metrics().quic_rtt.observe(stats.path.rtt.as_secs_f64());
+        previous = stats;
+
+        let ping_rtt = counters.ping_rtt.load(std::sync::atomic::Ordering::Relaxed);
+
+        sender.send(protocol::Event::StatsMeasured(
+            protocol::StatsMeasuredEvent {
+                connection_id: connection.stable_id(),
+                stats: protocol::TransportStats {
+                    rtt: (ping_rtt > 0).then(|| std::time::Duration::from_micros(ping_rtt)),
+                    smoothed_rtt: stats.path.rtt,
+                    congestion_window: stats.path.cwnd,
+                    congestion_events: stats.path.congestion_events,
+                    bytes_sent: stats.udp_tx.bytes,
+                    bytes_received: stats.udp_rx.bytes,
+                    packets_sent: stats.udp_tx.datagrams,
+                    packets_received: stats.udp_rx.datagrams,
+                    messages_sent: counters
+                        .messages_sent
+                        .load(std::sync::atomic::Ordering::Relaxed),
+                    messages_received: counters
+                        .messages_received
+                        .load(std::sync::atomic::Ordering::Relaxed),
+                },
+            },
+        ))?;
+    }
+}
+
+/// Holds back the instructions of the network plugin as long as the link conditions dictate.
+///
+/// Responses are handed to their request streams from here, so they are not queued behind
+/// writes of the outgoing stream.
+async fn handle_conditioner(
+    mut settings: tokio::sync::watch::Receiver<conditioner::Settings>,
+    codec: std::sync::Arc<dyn Codec>,
+    session: Session,
+    mut receiver: tokio::sync::mpsc::UnboundedReceiver<protocol::Outgoing>,
+    mut sender: tokio::sync::mpsc::UnboundedSender<protocol::Outgoing>,
+) -> crate::Result<()> {
+    let mut link = conditioner::Link::new(settings.borrow().get(session.connection_id()));
+
+    loop {
+        let due = link.next_due();
+
+        tokio::select! {
+            outgoing = receiver.recv() => match outgoing {
+                Some(outgoing) => link.push(std::time::Instant::now(), outgoing, &*codec),
+                None => break,
+            },
+            Ok(()) = settings.changed() => {
+                link.set_conditions(settings.borrow().get(session.connection_id()));
+            }
+            () = tokio::time::sleep_until(due.unwrap_or_else(std::time::Instant::now).into()), if due.is_some() => {}
+        }
+
+        release(&mut link, &session, &mut sender)?;
+    }
+
+    // the network plugin is gone, what it sent last, usually a close, still goes out on time.
+    while let Some(due) = link.next_due() {
+        tokio::time::sleep_until(due.into()).await;
+        release(&mut link, &session, &mut sender)?;
+    }
+
+    Ok(())
+}
+
+fn release(
+    link: &mut conditioner::Link,
+    session: &Session,
+    sender: &mut tokio::sync::mpsc::UnboundedSender<protocol::Outgoing>,
+) -> crate::Result<()> {
+    while let Some(outgoing) = link.pop(std::time::Instant::now()) {
+        session.dispatch(sender, outgoing)?;
+    }
+
+    Ok(())
+}
+
 pub(super) async fn handle_outgoing_stream(
     connection: quinn::Connection,
-    mut receiver: tokio::sync::mpsc::UnboundedReceiver<protocol::Payload>,
+    codec: std::sync::Arc<dyn Codec>,
+    session: Session,
+    mut receiver: tokio::sync::mpsc::UnboundedReceiver<protocol::Outgoing>,
 ) -> crate::Result<()> {
-    while let Some(payload) = receiver.recv().await {
+    let mut ordered_streams = std::collections::HashMap::new();
+
+    while let Some(outgoing) = receiver.recv().await {
+        match outgoing {
+            protocol::Outgoing::Message {
+                channel,
+                reliability: protocol::Reliability::ReliableOrdered,
+                payload,
+            } => {
+                let send = match ordered_streams.entry(channel) {
+                    std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        let mut send = connection.open_uni().await?;
+                        stream_opened("outgoing", "uni");
+                        send.write_all(&[channel]).await?;
+                        entry.insert(send)
+                    }
+                };
+
+                send.write_all(&frame(&encode(&*codec, &payload)?)?).await?;
+            }
+            protocol::Outgoing::Message {
+                channel,
+                reliability: protocol::Reliability::ReliableUnordered,
+                payload,
+            } => {
+                tokio::spawn(handle_outgoing_uni_request(
+                    connection.clone(),
+                    channel,
+                    encode(&*codec, &payload)?,
+                ));
+            }
+            protocol::Outgoing::Message {
+                channel,
+                reliability: protocol::Reliability::Unreliable,
+                payload,
+            } => {
+                let bytes = encode(&*codec, &payload)?;
+
+                let mut datagram = Vec::with_capacity(1 + bytes.len());
+                datagram.push(channel);
+                datagram.extend_from_slice(&bytes);
+
+                let fits = matches!(
+                    connection.max_datagram_size(),
+                    Some(max_datagram_size) if datagram.len() <= max_datagram_size
+                );
+
+                if fits {
+                    match connection.send_datagram(datagram.into()) {
+                        Ok(()) => continue,
+                        Err(quinn::SendDatagramError::ConnectionLost(error)) => {
+                            return Err(error.into())
+                        }
+                        Err(error) => {
+                            debug!(error = ?error, "datagram unsent, falling back to stream");
+                        }
+                    }
+                }
+
+                tokio::spawn(handle_outgoing_uni_request(
+                    connection.clone(),
+                    channel,
+                    bytes,
+                ));
+            }
+            protocol::Outgoing::Request {
+                request_id,
+                payload,
+                timeout,
+            } => {
+                tokio::spawn(handle_outgoing_request(
+                    connection.clone(),
+                    codec.clone(),
+                    session.clone(),
+                    request_id,
+                    payload,
+                    timeout,
+                ));
+            }
+            protocol::Outgoing::Response { request_id, .. } => {
+                return Err(transport::unanswerable(request_id));
+            }
+            protocol::Outgoing::Close { code, reason } => {
+                warn!(code = ?code, reason = reason, "closing connection");
+
+                connection.close(code.code(), reason.as_bytes());
+
+                return Ok(());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a single payload on its own stream, so it is not held back by other payloads.
+pub(super) async fn handle_outgoing_uni_request(
+    connection: quinn::Connection,
+    channel: u8,
+    bytes: Vec<u8>,
+) {
+    let result: crate::Result<()> = async {
         let mut send = connection.open_uni().await?;
+        stream_opened("outgoing", "uni");
 
-        send.write_all(&payload.serialize()?).await?;
+        send.write_all(&[channel]).await?;
+        send.write_all(&frame(&bytes)?).await?;
         send.finish().await?;
+
+        Ok(())
     }
+    .await;
 
-    Ok(())
+    if let Err(error) = result {
+        metrics().quic_stream_errors.inc();
+        warn!(channel = channel, error = %error, "payload unsent");
+    }
+}
+
+/// Spawns a stream handler, whose failure only affects that stream.
+fn spawn_stream(future: impl std::future::Future<Output = crate::Result<()>> + Send + 'static) {
+    tokio::spawn(async move {
+        if let Err(error) = future.await {
+            metrics().quic_stream_errors.inc();
+            warn!(error = %error, "stream failed");
+        }
+    });
+}
+
+fn stream_opened(direction: &str, kind: &str) {
+    metrics()
+        .quic_streams_opened
+        .with_label_values(&[direction, kind])
+        .inc();
+}
+
+fn encode(codec: &dyn Codec, payload: &protocol::Payload) -> crate::Result<Vec<u8>> {
+    let bytes = codec.serialize(payload)?;
+
+    metrics()
+        .quic_payloads
+        .with_label_values(&["sent", payload.kind()])
+        .inc();
+
+    Ok(bytes)
+}
+
+fn decode(codec: &dyn Codec, bytes: &[u8]) -> crate::Result<protocol::Payload> {
+    match codec.deserialize(bytes) {
+        Ok(payload) => {
+            metrics()
+                .quic_payloads
+                .with_label_values(&["received", payload.kind()])
+                .inc();
+
+            Ok(payload)
+        }
+        Err(error) => {
+            metrics().quic_decode_failures.inc();
+
+            Err(error)
+        }
+    }
+}
+
+fn frame(bytes: &[u8]) -> crate::Result<Vec<u8>> {
+    if bytes.len() > protocol::MAX_FRAME_SIZE {
+        return Err(crate::Error::Protocol(format!(
+            "frame of {} bytes exceeds limit",
+            bytes.len()
+        )));
+    }
+    let length = bytes.len() as u32;
+
+    let mut frame = Vec::with_capacity(4 + bytes.len());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(bytes);
+    Ok(frame)
+}
+
+pub(super) async fn handle_outgoing_request(
+    connection: quinn::Connection,
+    codec: std::sync::Arc<dyn Codec>,
+    session: Session,
+    request_id: u64,
+    payload: protocol::Payload,
+    timeout: std::time::Duration,
+) -> crate::Result<()> {
+    let response =
+        match tokio::time::timeout(timeout, request(&connection, &*codec, &payload)).await {
+            Ok(response) => response,
+            Err(_) => Err(protocol::RpcError::Timeout),
+        };
+
+    session.response(request_id, response)
+}
+
+async fn request(
+    connection: &quinn::Connection,
+    codec: &dyn Codec,
+    payload: &protocol::Payload,
+) -> Result<protocol::Payload, protocol::RpcError> {
+    let failed = |error: crate::Error| protocol::RpcError::Failed(error.to_string());
+
+    let (mut send, recv) = connection
+        .open_bi()
+        .await
+        .map_err(|_| protocol::RpcError::Closed)?;
+    stream_opened("outgoing", "bi");
+
+    send.write_all(&encode(codec, payload).map_err(failed)?)
+        .await
+        .map_err(|error| match error {
+            quinn::WriteError::ConnectionLost(_) => protocol::RpcError::Closed,
+            error => failed(error.into()),
+        })?;
+    send.finish().await.map_err(|error| match error {
+        quinn::WriteError::ConnectionLost(_) => protocol::RpcError::Closed,
+        error => failed(error.into()),
+    })?;
+
+    let response =
+        recv.read_to_end(protocol::MAX_FRAME_SIZE)
+            .await
+            .map_err(|error| match error {
+                quinn::ReadToEndError::Read(quinn::ReadError::ConnectionLost(_)) => {
+                    protocol::RpcError::Closed
+                }
+                error => failed(error.into()),
+            })?;
+
+    match decode(codec, &response).map_err(failed)? {
+        protocol::Payload::V1(protocol::Version1::Error(error)) => Err(error),
+        response => Ok(response),
+    }
 }
@@ -0,0 +1,411 @@
+use tokio::io::AsyncReadExt as _;
+
+use crate::protocol;
+
+/// Reads every certificate in the file, leaf first, ignoring other PEM blocks.
+pub(super) async fn read_certificates(path: &str) -> crate::Result<Vec<rustls::Certificate>> {
+    let certificates: Vec<_> = read_items(path)
+        .await?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(e) => Some(rustls::Certificate(e)),
+            _ => None,
+        })
+        .collect();
+
+    if certificates.is_empty() {
+        return Err(crate::Error::certificate(
+            path,
+            "no CERTIFICATE block found",
+        ));
+    }
+
+    Ok(certificates)
+}
+
+/// Reads the first PKCS#1, PKCS#8 or SEC1 private key in the file.
+pub(super) async fn read_private_key(path: &str) -> crate::Result<rustls::PrivateKey> {
+    read_items(path)
+        .await?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(e)
+            | rustls_pemfile::Item::PKCS8Key(e)
+            | rustls_pemfile::Item::ECKey(e) => Some(rustls::PrivateKey(e)),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            crate::Error::certificate(
+                path,
+                "no RSA PRIVATE KEY, PRIVATE KEY or EC PRIVATE KEY block found",
+            )
+        })
+}
+
+/// Trusts every certificate found in the files.
+pub(super) async fn read_roots(paths: &[String]) -> crate::Result<rustls::RootCertStore> {
+    let mut roots = rustls::RootCertStore::empty();
+    for path in paths {
+        for certificate in read_certificates(path).await? {
+            roots
+                .add(&certificate)
+                .map_err(|error| crate::Error::certificate(path, error))?;
+        }
+    }
+
+    if roots.is_empty() {
+        return Err(crate::Error::certificate(
+            "authorities",
+            "no trust roots configured",
+        ));
+    }
+
+    Ok(roots)
+}
+
+async fn read_items(path: &str) -> crate::Result<Vec<rustls_pemfile::Item>> {
+    let contents = read(path).await?;
+
+    rustls_pemfile::read_all(&mut &*contents)
+        .map_err(|error| crate::Error::certificate(path, error))
+}
+
+async fn read(path: &str) -> crate::Result<Vec<u8>> {
+    let read = async {
+        let mut file = tokio::fs::File::open(path).await?;
+
+        let mut contents = vec![];
+        file.read_to_end(&mut contents).await?;
+
+        Ok::<_, std::io::Error>(contents)
+    };
+
+    read.await
+        .map_err(|error| crate::Error::certificate(path, error))
+}
+
+/// Checks that the private key belongs to the certificate, so a half rotated pair is rejected
+/// before it is served.
+#[cfg(feature = "server")]
+pub(super) fn verify_key_pair(
+    name: &str,
+    certificate: &rustls::Certificate,
+    private_key: &rustls::PrivateKey,
+) -> crate::Result<()> {
+    let signer = rustls::sign::any_supported_type(private_key)
+        .map_err(|error| crate::Error::certificate(name, error))?
+        .choose_scheme(&[
+            rustls::SignatureScheme::ED25519,
+            rustls::SignatureScheme::ECDSA_NISTP256_SHA256,
+            rustls::SignatureScheme::ECDSA_NISTP384_SHA384,
+            rustls::SignatureScheme::RSA_PSS_SHA256,
+        ])
+        .ok_or_else(|| crate::Error::certificate(name, "unsupported private key"))?;
+
+    let algorithm: &'static dyn ring::signature::VerificationAlgorithm = match signer.scheme() {
+        rustls::SignatureScheme::ED25519 => &ring::signature::ED25519,
+        rustls::SignatureScheme::ECDSA_NISTP256_SHA256 => &ring::signature::ECDSA_P256_SHA256_ASN1,
+        rustls::SignatureScheme::ECDSA_NISTP384_SHA384 => &ring::signature::ECDSA_P384_SHA384_ASN1,
+        _ => &ring::signature::RSA_PSS_2048_8192_SHA256,
+    };
+
+    let message = b"bevy-technical-demo key pair check";
+    let signature = signer.sign(message)?;
+
+    let (_, certificate) = x509_parser::parse_x509_certificate(&certificate.0)
+        .map_err(|error| crate::Error::certificate(name, error))?;
+
+    ring::signature::UnparsedPublicKey::new(
+        algorithm,
+        &certificate.public_key().subject_public_key.data,
+    )
+    .verify(message, &signature)
+    .map_err(|_| crate::Error::certificate(name, "private key does not match certificate"))
+}
+
+/// Trusts a server whose certificate public key hashes to one of the pinned fingerprints,
+/// whoever issued it.
+#[cfg(feature = "client")]
+pub(super) struct PinnedServerVerifier {
+    fingerprints: Vec<Vec<u8>>,
+}
+
+#[cfg(feature = "client")]
+impl PinnedServerVerifier {
+    pub(super) fn new(fingerprints: &[String]) -> crate::Result<PinnedServerVerifier> {
+        let fingerprints = fingerprints
+            .iter()
+            .map(|fingerprint| parse_fingerprint(fingerprint))
+            .collect::<crate::Result<Vec<_>>>()?;
+
+        if fingerprints.is_empty() {
+            return Err(crate::Error::certificate(
+                "pinned fingerprints",
+                "no fingerprint configured",
+            ));
+        }
+
+        Ok(PinnedServerVerifier { fingerprints })
+    }
+}
+
+#[cfg(feature = "client")]
+impl rustls::client::ServerCertVerifier for PinnedServerVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        let fingerprint =
+            spki_fingerprint(end_entity).map_err(|_| rustls::Error::InvalidCertificateEncoding)?;
+
+        if self.fingerprints.contains(&fingerprint) {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificateData(
+                "public key is not pinned".into(),
+            ))
+        }
+    }
+}
+
+/// Trusts any server, the handshake signature is still checked against the presented
+/// certificate.
+#[cfg(feature = "client")]
+pub(super) struct InsecureServerVerifier;
+
+#[cfg(feature = "client")]
+impl rustls::client::ServerCertVerifier for InsecureServerVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+/// SHA-256 of the DER encoded SubjectPublicKeyInfo of the certificate.
+#[cfg(feature = "client")]
+fn spki_fingerprint(certificate: &rustls::Certificate) -> crate::Result<Vec<u8>> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(&certificate.0)
+        .map_err(|error| crate::Error::certificate("presented by peer", error))?;
+
+    Ok(
+        ring::digest::digest(&ring::digest::SHA256, certificate.public_key().raw)
+            .as_ref()
+            .to_vec(),
+    )
+}
+
+/// Accepts hex with or without `:` separators, as printed by `certificates fingerprint`.
+#[cfg(feature = "client")]
+fn parse_fingerprint(fingerprint: &str) -> crate::Result<Vec<u8>> {
+    let hex: Vec<u8> = fingerprint.bytes().filter(|&byte| byte != b':').collect();
+
+    let bytes = hex
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .filter(|bytes| bytes.len() == 32 && hex.len() == 64);
+
+    bytes.ok_or_else(|| {
+        crate::Error::certificate(fingerprint, "expected a hex encoded SHA-256 fingerprint")
+    })
+}
+
+/// Identity of the verified certificate the peer presented, if any.
+pub(super) fn peer_identity(connection: &quinn::Connection) -> Option<protocol::PeerIdentity> {
+    let certificates = connection
+        .peer_identity()?
+        .downcast::<Vec<rustls::Certificate>>()
+        .ok()?;
+
+    match identity(certificates.first()?) {
+        Ok(identity) => Some(identity),
+        Err(error) => {
+            bevy::log::warn!(error = %error, "unreadable peer certificate");
+            None
+        }
+    }
+}
+
+fn identity(certificate: &rustls::Certificate) -> crate::Result<protocol::PeerIdentity> {
+    let (_, certificate) = x509_parser::parse_x509_certificate(&certificate.0)
+        .map_err(|error| crate::Error::certificate("presented by peer", error))?;
+
+    let subject_alt_names = match certificate
+        .subject_alternative_name()
+        .map_err(|error| crate::Error::certificate("presented by peer", error))?
+    {
+        Some(extension) => extension
+            .value
+            .general_names
+            .iter()
+            .map(|name| match name {
+                x509_parser::extensions::GeneralName::DNSName(name)
+                | x509_parser::extensions::GeneralName::RFC822Name(name)
+                | x509_parser::extensions::GeneralName::URI(name) => (*name).to_string(),
+                x509_parser::extensions::GeneralName::IPAddress(&[a, b, c, d]) => {
+                    std::net::Ipv4Addr::new(a, b, c, d).to_string()
+                }
+                x509_parser::extensions::GeneralName::IPAddress(address) => {
+                    <[u8; 16]>::try_from(*address)
+                        .map(|address| std::net::Ipv6Addr::from(address).to_string())
+                        .unwrap_or_else(|_| name.to_string())
+                }
+                name => name.to_string(),
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(protocol::PeerIdentity {
+        subject: certificate.subject().to_string(),
+        subject_alt_names,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity() {
+        let mut params = rcgen::CertificateParams::new(vec!["client.localhost".to_string()]);
+        params.subject_alt_names.push(rcgen::SanType::IpAddress(
+            std::net::Ipv4Addr::LOCALHOST.into(),
+        ));
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "client");
+        let certificate = rcgen::Certificate::from_params(params).unwrap();
+
+        let result = identity(&rustls::Certificate(certificate.serialize_der().unwrap())).unwrap();
+
+        assert_eq!(result.subject, "CN=client");
+        assert_eq!(
+            result.subject_alt_names,
+            vec!["client.localhost".to_string(), "127.0.0.1".to_string()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let leaf = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let intermediate = rcgen::generate_simple_self_signed(vec!["ca".into()]).unwrap();
+
+        // the key and an unrelated block before the chain
+        let contents = format!(
+            "{}-----BEGIN X509 CRL-----\nAAAA\n-----END X509 CRL-----\n{}{}",
+            leaf.serialize_private_key_pem(),
+            leaf.serialize_pem().unwrap(),
+            intermediate.serialize_pem().unwrap(),
+        );
+        let path = std::env::temp_dir().join(format!("tls-{}.pem", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        tokio::fs::write(&path, contents).await.unwrap();
+
+        let certificates = read_certificates(&path).await.unwrap();
+        let private_key = read_private_key(&path).await.unwrap();
+        let roots = read_roots(std::slice::from_ref(&path)).await.unwrap();
+
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(certificates.len(), 2);
+        assert_eq!(
+            identity(&certificates[0]).unwrap().subject_alt_names,
+            vec!["localhost".to_string()]
+        );
+        assert_eq!(private_key.0, leaf.serialize_private_key_der());
+        assert_eq!(roots.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_read_missing() {
+        let path = std::env::temp_dir().join(format!("tls-missing-{}.pem", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        tokio::fs::write(&path, "no pem here").await.unwrap();
+
+        let certificates = read_certificates(&path).await;
+        let private_key = read_private_key(&path).await;
+
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert!(matches!(
+            certificates,
+            Err(crate::Error::Certificate { .. })
+        ));
+        assert!(matches!(private_key, Err(crate::Error::Certificate { .. })));
+    }
+
+    #[cfg(feature = "client")]
+    #[test]
+    fn test_pinned_server_verifier() {
+        use rustls::client::ServerCertVerifier as _;
+
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+        let pinned = ring::digest::digest(
+            &ring::digest::SHA256,
+            &certificate.get_key_pair().public_key_der(),
+        )
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":");
+
+        let verifier = PinnedServerVerifier::new(&[pinned]).unwrap();
+        let verify = |certificate: &rcgen::Certificate| {
+            verifier.verify_server_cert(
+                &rustls::Certificate(certificate.serialize_der().unwrap()),
+                &[],
+                &"localhost".try_into().unwrap(),
+                &mut std::iter::empty(),
+                &[],
+                std::time::SystemTime::now(),
+            )
+        };
+
+        assert!(verify(&certificate).is_ok());
+        assert!(verify(&other).is_err());
+        assert!(PinnedServerVerifier::new(&[]).is_err());
+        assert!(PinnedServerVerifier::new(&["00".into()]).is_err());
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn test_verify_key_pair() {
+        let certificate = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let other = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+
+        let der = rustls::Certificate(certificate.serialize_der().unwrap());
+
+        assert!(verify_key_pair(
+            "tls.key",
+            &der,
+            &rustls::PrivateKey(certificate.serialize_private_key_der())
+        )
+        .is_ok());
+        assert!(verify_key_pair(
+            "tls.key",
+            &der,
+            &rustls::PrivateKey(other.serialize_private_key_der())
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,344 @@
+//! Field level differences between snapshots of the replicated entities.
+
+use crate::protocol::{self, Delta};
+
+/// Serialized components by kind of every replicated entity, by server entity bits.
+pub(crate) type Entities =
+    std::collections::BTreeMap<u64, std::collections::BTreeMap<String, serde_json::Value>>;
+
+/// Difference turning `old` into `new`, `None` when they are equal.
+pub(crate) fn diff(old: &serde_json::Value, new: &serde_json::Value) -> Option<Delta> {
+    if old == new {
+        return None;
+    }
+
+    match (old, new) {
+        (serde_json::Value::Object(old), serde_json::Value::Object(new)) => {
+            let mut fields = std::collections::BTreeMap::new();
+
+            for (key, value) in new {
+                let delta = match old.get(key) {
+                    Some(old) => diff(old, value),
+                    None => Some(Delta::Set(value.clone())),
+                };
+                if let Some(delta) = delta {
+                    fields.insert(key.clone(), delta);
+                }
+            }
+            for key in old.keys().filter(|key| !new.contains_key(*key)) {
+                fields.insert(key.clone(), Delta::Removed);
+            }
+
+            Some(Delta::Fields(fields))
+        }
+        _ => Some(Delta::Set(new.clone())),
+    }
+}
+
+/// Applies a difference produced by [`diff`] to the value it was computed against.
+pub(crate) fn apply(
+    old: Option<&serde_json::Value>,
+    delta: &Delta,
+) -> crate::Result<serde_json::Value> {
+    match (old, delta) {
+        (_, Delta::Set(value)) => Ok(value.clone()),
+        (Some(serde_json::Value::Object(old)), Delta::Fields(fields)) => {
+            let mut object = old.clone();
+
+            for (key, delta) in fields {
+                match delta {
+                    Delta::Removed => {
+                        object.remove(key);
+                    }
+                    delta => {
+                        let value = apply(object.get(key), delta)?;
+                        object.insert(key.clone(), value);
+                    }
+                }
+            }
+
+            Ok(serde_json::Value::Object(object))
+        }
+        (_, Delta::Fields(_)) => Err(crate::Error::Protocol(
+            "fields changed on a value that is not an object".into(),
+        )),
+        (_, Delta::Removed) => Err(crate::Error::Protocol(
+            "value removed outside of an object".into(),
+        )),
+    }
+}
+
+/// Encodes `current` against the baseline, listing every entity when there is none.
+pub(crate) fn snapshot(
+    tick: u64,
+    baseline: Option<(u64, &Entities)>,
+    current: &Entities,
+) -> protocol::Replication {
+    let empty = Entities::new();
+    let old = baseline.map_or(&empty, |(_, entities)| entities);
+
+    let entities = current
+        .iter()
+        .filter_map(|(&entity, components)| {
+            let old = old.get(&entity);
+
+            let update = protocol::EntityUpdate {
+                entity,
+                components: components
+                    .iter()
+                    .filter_map(|(kind, value)| {
+                        let delta = match old.and_then(|old| old.get(kind)) {
+                            Some(old) => diff(old, value)?,
+                            None => Delta::Set(value.clone()),
+                        };
+                        Some((kind.clone(), delta))
+                    })
+                    .collect(),
+                removed: old
+                    .into_iter()
+                    .flat_map(|old| old.keys())
+                    .filter(|kind| !components.contains_key(*kind))
+                    .cloned()
+                    .collect(),
+            };
+
+            // an entity new to the baseline is listed even without components.
+            let unchanged =
+                old.is_some() && update.components.is_empty() && update.removed.is_empty();
+            (!unchanged).then_some(update)
+        })
+        .collect();
+
+    protocol::Replication {
+        tick,
+        baseline: baseline.map(|(tick, _)| tick),
+        entities,
+        despawned: old
+            .keys()
+            .filter(|entity| !current.contains_key(*entity))
+            .copied()
+            .collect(),
+        input: None,
+    }
+}
+
+/// Rebuilds the entities a snapshot was encoded from, given the baseline it names.
+pub(crate) fn reconstruct(
+    baseline: Option<&Entities>,
+    replication: &protocol::Replication,
+) -> crate::Result<Entities> {
+    let mut entities = baseline.cloned().unwrap_or_default();
+
+    for entity in &replication.despawned {
+        entities.remove(entity);
+    }
+
+    for update in &replication.entities {
+        let components = entities.entry(update.entity).or_default();
+
+        for kind in &update.removed {
+            components.remove(kind);
+        }
+        for (kind, delta) in &update.components {
+            let value = apply(components.get(kind), delta)?;
+            components.insert(kind.clone(), value);
+        }
+    }
+
+    Ok(entities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: [&str; 4] = ["a", "b", "c", "d"];
+
+    fn value(rng: &fastrand::Rng, depth: usize) -> serde_json::Value {
+        match rng.usize(0..if depth == 0 { 4 } else { 6 }) {
+            0 => serde_json::Value::Null,
+            1 => rng.bool().into(),
+            2 => (rng.i64(-100..100) as f64 / 4.0).into(),
+            3 => KEYS[rng.usize(..KEYS.len())].into(),
+            4 => (0..rng.usize(0..3))
+                .map(|_| value(rng, depth - 1))
+                .collect::<Vec<_>>()
+                .into(),
+            _ => object(rng, depth - 1),
+        }
+    }
+
+    fn object(rng: &fastrand::Rng, depth: usize) -> serde_json::Value {
+        KEYS.iter()
+            .filter(|_| rng.bool())
+            .map(|key| (key.to_string(), value(rng, depth)))
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+
+    /// Changes some fields of an object, anywhere in it.
+    fn mutate(rng: &fastrand::Rng, original: &serde_json::Value) -> serde_json::Value {
+        match original {
+            serde_json::Value::Object(object) => {
+                let mut object = object.clone();
+                for key in KEYS {
+                    match rng.usize(0..6) {
+                        0 => {
+                            object.remove(key);
+                        }
+                        1 => {
+                            object.insert(key.into(), value(rng, 2));
+                        }
+                        2 => {
+                            if let Some(field) = object.get(key).map(|x| mutate(rng, x)) {
+                                object.insert(key.into(), field);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                object.into()
+            }
+            _ if rng.bool() => value(rng, 2),
+            original => original.clone(),
+        }
+    }
+
+    fn entities(rng: &fastrand::Rng, previous: &Entities) -> Entities {
+        let mut entities = previous.clone();
+
+        for entity in 0..8 {
+            match rng.usize(0..8) {
+                0 => {
+                    entities.remove(&entity);
+                }
+                1 => {
+                    entities.insert(entity, std::collections::BTreeMap::new());
+                }
+                _ => {
+                    if let Some(components) = entities.get_mut(&entity) {
+                        for kind in ["position", "health"] {
+                            match rng.usize(0..4) {
+                                0 => {
+                                    components.remove(kind);
+                                }
+                                1 => {
+                                    let value = components
+                                        .get(kind)
+                                        .map_or_else(|| object(rng, 2), |x| mutate(rng, x));
+                                    components.insert(kind.into(), value);
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        entities
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = serde_json::json!({"x": 1.0, "y": 2.0, "name": "a", "tags": [1]});
+        let new = serde_json::json!({"x": 1.0, "y": 3.0, "tags": [1, 2]});
+
+        assert_eq!(diff(&old, &old), None);
+        assert_eq!(
+            diff(&old, &new),
+            Some(Delta::Fields(
+                [
+                    ("name".to_string(), Delta::Removed),
+                    ("tags".to_string(), Delta::Set(serde_json::json!([1, 2]))),
+                    ("y".to_string(), Delta::Set(serde_json::json!(3.0))),
+                ]
+                .into_iter()
+                .collect()
+            ))
+        );
+        assert!(apply(Some(&serde_json::json!(1)), &diff(&old, &new).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_reconstruct_values() {
+        let rng = fastrand::Rng::with_seed(7);
+
+        for _ in 0..1000 {
+            let old = object(&rng, 3);
+            let new = mutate(&rng, &old);
+
+            let reconstructed = match diff(&old, &new) {
+                Some(delta) => apply(Some(&old), &delta).unwrap(),
+                None => old.clone(),
+            };
+            assert_eq!(reconstructed, new);
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_snapshots() {
+        let rng = fastrand::Rng::with_seed(11);
+        let codec = crate::codec::Format::MessagePack.codec();
+        let mut history = vec![Entities::new()];
+
+        for tick in 1..300 {
+            let current = entities(&rng, history.last().unwrap());
+
+            // any earlier snapshot may be the acknowledged one, or none after a loss.
+            let baseline_tick = rng.usize(0..=history.len());
+            let baseline = history
+                .get(baseline_tick)
+                .map(|entities| (baseline_tick as u64, entities));
+
+            let replication = snapshot(tick, baseline, &current);
+
+            let payload = protocol::Payload::V1(protocol::Version1::Replication(replication));
+            let payload = codec
+                .deserialize(&codec.serialize(&payload).unwrap())
+                .unwrap();
+            let replication = match payload {
+                protocol::Payload::V1(protocol::Version1::Replication(replication)) => replication,
+                payload => panic!("unexpected {payload:?}"),
+            };
+
+            assert_eq!(replication.baseline, baseline.map(|(tick, _)| tick));
+            assert_eq!(
+                reconstruct(baseline.map(|(_, entities)| entities), &replication).unwrap(),
+                current
+            );
+
+            history.push(current);
+        }
+    }
+
+    #[test]
+    fn test_snapshot_size() {
+        let mut baseline = Entities::new();
+        for entity in 0..100 {
+            baseline.entry(entity).or_default().insert(
+                "position".into(),
+                serde_json::json!({"x": entity, "y": 0, "z": 0}),
+            );
+        }
+        let mut current = baseline.clone();
+        current.get_mut(&3).unwrap().insert(
+            "position".into(),
+            serde_json::json!({"x": 3, "y": 1, "z": 0}),
+        );
+
+        let full = snapshot(2, None, &current);
+        let delta = snapshot(2, Some((1, &baseline)), &current);
+
+        assert_eq!(full.entities.len(), 100);
+        assert_eq!(delta.entities.len(), 1);
+        assert_eq!(
+            delta.entities[0].components["position"],
+            Delta::Fields(
+                [("y".to_string(), Delta::Set(1.into()))]
+                    .into_iter()
+                    .collect()
+            )
+        );
+    }
+}
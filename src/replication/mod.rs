@@ -0,0 +1,945 @@
+//! Mirrors server entities on clients, the server being the authority on their state.
+//!
+//! The server marks entities with [`Replicated`] and keeps a snapshot of their [`Replicable`]
+//! components, refreshed every frame from the components that were added, changed or removed.
+//! Each client is sent the snapshot unreliably on [`protocol::REPLICATION_CHANNEL`], encoded
+//! against the latest one it acknowledged so only changed fields travel. A client without an
+//! acknowledged snapshot still in the history, after connecting, resuming or losing too many
+//! snapshots, gets a full one. A new snapshot is only taken when something changed, and clients
+//! that acknowledged the latest one are not sent anything. Clients spawn a local entity per
+//! server entity, see [`EntityMap`].
+//!
+//! A snapshot the transport cannot carry in one frame closes the connection, the client would
+//! otherwise never catch up.
+//!
+//! Components registered with [`Plugin::with_predicted`] are changed by clients ahead of the
+//! server, see [`prediction`].
+
+use bevy::{ecs::system::EntityCommands, prelude::*};
+
+use crate::{
+    network::{Connection, Detached},
+    protocol,
+};
+
+mod delta;
+pub mod prediction;
+
+/// Snapshots kept as baselines, older acknowledgements fall back to full snapshots.
+const HISTORY: usize = 64;
+
+/// A component the server replicates, registered with [`Plugin::with_component`].
+pub trait Replicable: Component + serde::Serialize + serde::de::DeserializeOwned {
+    /// Identifies the component on the wire, unique among the registered components.
+    const KIND: &'static str;
+}
+
+/// Marks a server entity whose [`Replicable`] components are mirrored on clients, removing it
+/// despawns the entity on clients.
+#[derive(Clone, Component, Debug, Default)]
+pub struct Replicated;
+
+/// Identifies the server entity a client entity mirrors.
+#[derive(Clone, Component, Copy, Debug, Deref, PartialEq, Eq)]
+pub struct ServerEntity(pub u64);
+
+/// Client entities mirroring server entities, by the bits of the server entity.
+#[derive(Default)]
+pub struct EntityMap {
+    entities: std::collections::HashMap<u64, Entity>,
+    tick: u64,
+}
+
+impl EntityMap {
+    /// Local entity mirroring the server entity.
+    #[must_use]
+    pub fn client_entity(&self, server_entity: u64) -> Option<Entity> {
+        self.entities.get(&server_entity).copied()
+    }
+
+    /// Latest server tick applied.
+    #[must_use]
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+}
+
+/// Which end of the replication an app is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Server,
+    Client,
+}
+
+struct Registration {
+    kind: &'static str,
+    functions: Functions,
+    add_systems: fn(&mut App, Role),
+}
+
+#[derive(Clone, Copy)]
+struct Functions {
+    insert: fn(&mut EntityCommands<'_, '_, '_>, serde_json::Value) -> serde_json::Result<()>,
+    remove: fn(&mut EntityCommands<'_, '_, '_>),
+}
+
+/// Functions applying the registered components on clients, by kind.
+#[derive(Default)]
+struct Registry {
+    functions: std::collections::HashMap<&'static str, Functions>,
+}
+
+pub struct Plugin {
+    role: Role,
+    components: Vec<Registration>,
+}
+
+impl Plugin {
+    /// Snapshots [`Replicated`] entities and sends them to every connection.
+    #[must_use]
+    pub fn server() -> Plugin {
+        Plugin {
+            role: Role::Server,
+            components: Vec::new(),
+        }
+    }
+
+    /// Applies and acknowledges the snapshots received from the server.
+    #[must_use]
+    pub fn client() -> Plugin {
+        Plugin {
+            role: Role::Client,
+            components: Vec::new(),
+        }
+    }
+
+    /// Registers the component `T`, both ends must register the same components.
+    ///
+    /// # Panics
+    ///
+    /// If another component uses the same kind.
+    #[must_use]
+    pub fn with_component<T: Replicable>(self) -> Plugin {
+        self.register::<T>(
+            Functions {
+                insert: insert::<T>,
+                remove: remove::<T>,
+            },
+            add_systems::<T>,
+        )
+    }
+
+    /// Registers the component `T` like [`Plugin::with_component`], clients may change it ahead
+    /// of the server with inputs, see [`prediction`].
+    ///
+    /// # Panics
+    ///
+    /// If another component uses the same kind.
+    #[must_use]
+    pub fn with_predicted<T: prediction::Predicted>(self) -> Plugin {
+        self.register::<T>(
+            Functions {
+                insert: prediction::insert::<T>,
+                remove: prediction::remove::<T>,
+            },
+            prediction::add_systems::<T>,
+        )
+    }
+
+    fn register<T: Replicable>(
+        mut self,
+        functions: Functions,
+        add_systems: fn(&mut App, Role),
+    ) -> Plugin {
+        assert!(
+            self.components.iter().all(|x| x.kind != T::KIND),
+            "component {} reuses kind {}",
+            std::any::type_name::<T>(),
+            T::KIND
+        );
+
+        self.components.push(Registration {
+            kind: T::KIND,
+            functions,
+            add_systems,
+        });
+        self
+    }
+}
+
+impl bevy::prelude::Plugin for Plugin {
+    fn build(&self, app: &mut App) {
+        for registration in &self.components {
+            (registration.add_systems)(app, self.role);
+        }
+
+        match self.role {
+            Role::Server => {
+                app.init_resource::<Snapshots>()
+                    .init_resource::<Peers>()
+                    .init_resource::<prediction::Sequences>()
+                    .add_system(acknowledge)
+                    .add_system_to_stage(
+                        CoreStage::PostUpdate,
+                        collect_entities.label(Label::Collect),
+                    )
+                    .add_system_to_stage(CoreStage::PostUpdate, send.after(Label::Collect));
+            }
+            Role::Client => {
+                app.insert_resource(Registry {
+                    functions: self
+                        .components
+                        .iter()
+                        .map(|x| (x.kind, x.functions))
+                        .collect(),
+                })
+                .init_resource::<EntityMap>()
+                .init_resource::<Baselines>()
+                .add_system(apply);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, SystemLabel)]
+enum Label {
+    Collect,
+}
+
+/// Replicated entities on the server and the snapshots recently sent.
+#[derive(Default)]
+struct Snapshots {
+    tick: u64,
+    current: delta::Entities,
+    /// Whether `current` changed since the snapshot of `tick` was taken.
+    changed: bool,
+    history: std::collections::VecDeque<(u64, delta::Entities)>,
+}
+
+impl Snapshots {
+    fn get(&self, tick: u64) -> Option<&delta::Entities> {
+        self.history
+            .iter()
+            .find(|(x, _)| *x == tick)
+            .map(|(_, entities)| entities)
+    }
+}
+
+/// Replication state of a connection entity.
+struct Peer {
+    /// Transport connection the acknowledgement was received on, a resumed connection gets a
+    /// new id and starts over from a full snapshot.
+    connection_id: usize,
+    acknowledged: Option<u64>,
+    /// Sequence of the latest input the snapshot of the current tick acknowledges.
+    input: Option<u64>,
+}
+
+#[derive(Default)]
+struct Peers {
+    peers: std::collections::HashMap<Entity, Peer>,
+}
+
+/// Snapshots received by a client, to decode the ones encoded against them.
+#[derive(Default)]
+struct Baselines {
+    connection_id: Option<usize>,
+    /// Snapshot the mirrored entities currently reflect.
+    current: delta::Entities,
+    /// Sequence of the latest input `current` reflects.
+    input: Option<u64>,
+    history: std::collections::VecDeque<(u64, delta::Entities)>,
+}
+
+fn add_systems<T: Replicable>(app: &mut App, role: Role) {
+    if role == Role::Server {
+        app.add_system_to_stage(CoreStage::PostUpdate, collect::<T>.label(Label::Collect));
+    }
+}
+
+fn collect_entities(
+    mut snapshots: ResMut<Snapshots>,
+    query: Query<(Entity, ChangeTrackers<Replicated>)>,
+    removed: RemovedComponents<Replicated>,
+) {
+    for entity in removed.iter() {
+        if snapshots.current.remove(&entity.to_bits()).is_some() {
+            snapshots.changed = true;
+        }
+    }
+
+    for (entity, tracker) in query.iter() {
+        if tracker.is_added() {
+            snapshots.current.entry(entity.to_bits()).or_default();
+            snapshots.changed = true;
+        }
+    }
+}
+
+fn collect<T: Replicable>(
+    mut snapshots: ResMut<Snapshots>,
+    query: Query<(Entity, &T, ChangeTrackers<T>, ChangeTrackers<Replicated>)>,
+    replicated: Query<(), With<Replicated>>,
+    removed: RemovedComponents<T>,
+) {
+    for entity in removed.iter() {
+        if replicated.contains(entity) {
+            if let Some(components) = snapshots.current.get_mut(&entity.to_bits()) {
+                if components.remove(T::KIND).is_some() {
+                    snapshots.changed = true;
+                }
+            }
+        }
+    }
+
+    for (entity, component, tracker, replicated_tracker) in query.iter() {
+        // components inserted before the entity was marked were never collected.
+        if !tracker.is_changed() && !replicated_tracker.is_added() {
+            continue;
+        }
+
+        match serde_json::to_value(component) {
+            Ok(value) => {
+                snapshots
+                    .current
+                    .entry(entity.to_bits())
+                    .or_default()
+                    .insert(T::KIND.into(), value);
+                snapshots.changed = true;
+            }
+            Err(error) => warn!(kind = T::KIND, error = %error, "component not replicated"),
+        }
+    }
+}
+
+fn acknowledge(
+    snapshots: Res<Snapshots>,
+    mut peers: ResMut<Peers>,
+    mut reader: EventReader<protocol::MessageReceivedEvent>,
+) {
+    for event in reader.iter() {
+        if event.channel != protocol::REPLICATION_CHANNEL {
+            continue;
+        }
+
+        let tick = match event.payload {
+            protocol::Payload::V1(protocol::Version1::Acknowledge(tick)) => tick,
+            ref payload => {
+                warn!(
+                    connection_id = event.connection_id,
+                    kind = payload.kind(),
+                    "unexpected replication payload"
+                );
+                continue;
+            }
+        };
+
+        // acknowledgements are unreliable, so they may arrive out of order or twice. `None`
+        // orders before every tick.
+        let peer = peers
+            .peers
+            .values_mut()
+            .find(|peer| peer.connection_id == event.connection_id);
+        if let Some(peer) = peer {
+            if tick <= snapshots.tick && peer.acknowledged < Some(tick) {
+                peer.acknowledged = Some(tick);
+            }
+        }
+    }
+}
+
+fn send(
+    mut snapshots: ResMut<Snapshots>,
+    mut peers: ResMut<Peers>,
+    sequences: Res<prediction::Sequences>,
+    query: Query<(Entity, &Connection), Without<Detached>>,
+    mut closes: EventWriter<protocol::CloseConnectionEvent>,
+) {
+    // inputs are acknowledged by snapshots, a client whose input was executed needs a new one
+    // even when the input changed nothing.
+    let executed = peers
+        .peers
+        .iter()
+        .any(|(&entity, peer)| peer.input != sequences.get(entity));
+
+    if snapshots.changed || executed {
+        snapshots.changed = false;
+        snapshots.tick += 1;
+
+        let tick = snapshots.tick;
+        let current = snapshots.current.clone();
+        snapshots.history.push_back((tick, current));
+        if snapshots.history.len() > HISTORY {
+            snapshots.history.pop_front();
+        }
+    }
+
+    let tick = snapshots.tick;
+
+    for (entity, connection) in query.iter() {
+        let peer = peers.peers.entry(entity).or_insert(Peer {
+            connection_id: connection.connection_id(),
+            acknowledged: None,
+            input: None,
+        });
+        if peer.connection_id != connection.connection_id() {
+            peer.connection_id = connection.connection_id();
+            peer.acknowledged = None;
+        }
+        peer.input = sequences.get(entity);
+
+        if peer.acknowledged == Some(tick) {
+            continue;
+        }
+
+        let baseline = peer
+            .acknowledged
+            .and_then(|tick| Some((tick, snapshots.get(tick)?)));
+
+        let span = info_span!("connection", connection_id = connection.connection_id());
+        let _guard = span.enter();
+
+        if baseline.is_none() {
+            trace!(
+                entities = snapshots.current.len(),
+                tick,
+                "sending full snapshot"
+            );
+        }
+
+        let mut replication = delta::snapshot(tick, baseline, &snapshots.current);
+        replication.input = peer.input;
+        let payload = protocol::Payload::V1(protocol::Version1::Replication(replication));
+
+        // a snapshot too large for a datagram is sent on a stream by the transport, as long as
+        // it fits in a frame.
+        match connection.codec().codec().serialize(&payload) {
+            Ok(bytes) if bytes.len() > protocol::MAX_FRAME_SIZE => {
+                error!(
+                    bytes = bytes.len(),
+                    "snapshot too large, closing connection"
+                );
+                closes.send(protocol::CloseConnectionEvent {
+                    connection_id: connection.connection_id(),
+                    reason: "snapshot too large".into(),
+                });
+                continue;
+            }
+            Ok(_) => {}
+            Err(error) => {
+                warn!(error = %error, "snapshot not encoded");
+                continue;
+            }
+        }
+
+        let sent = connection.send(protocol::Outgoing::Message {
+            channel: protocol::REPLICATION_CHANNEL,
+            reliability: protocol::Reliability::Unreliable,
+            payload,
+        });
+
+        if !sent {
+            warn!("connection closed");
+        }
+    }
+
+    // detached connections get a full snapshot when they resume.
+    peers.peers.retain(|entity, _| query.contains(*entity));
+}
+
+fn apply(
+    mut commands: Commands,
+    registry: Res<Registry>,
+    mut entity_map: ResMut<EntityMap>,
+    mut baselines: ResMut<Baselines>,
+    query: Query<&Connection, Without<Detached>>,
+    mut reader: EventReader<protocol::MessageReceivedEvent>,
+) {
+    let mut acknowledged = None;
+
+    for event in reader.iter() {
+        if event.channel != protocol::REPLICATION_CHANNEL {
+            continue;
+        }
+
+        let span = info_span!("connection", connection_id = event.connection_id);
+        let _guard = span.enter();
+
+        let replication = match &event.payload {
+            protocol::Payload::V1(protocol::Version1::Replication(replication)) => replication,
+            payload => {
+                warn!(kind = payload.kind(), "unexpected replication payload");
+                continue;
+            }
+        };
+
+        // a new transport connection may belong to a restarted server with earlier ticks.
+        let same_connection = baselines.connection_id == Some(event.connection_id);
+        if same_connection && replication.tick <= entity_map.tick {
+            trace!(tick = replication.tick, "stale snapshot dropped");
+            continue;
+        }
+
+        let baseline = match replication.baseline {
+            None => None,
+            Some(tick) if same_connection => match baselines.get(tick) {
+                Some(baseline) => Some(baseline),
+                None => {
+                    warn!(baseline = tick, "snapshot baseline missing");
+                    continue;
+                }
+            },
+            Some(_) => continue,
+        };
+
+        let entities = match delta::reconstruct(baseline, replication) {
+            Ok(entities) => entities,
+            Err(error) => {
+                warn!(error = %error, "snapshot dropped");
+                continue;
+            }
+        };
+
+        mirror(
+            &mut commands,
+            &registry,
+            &mut entity_map,
+            &baselines.current,
+            &entities,
+        );
+
+        if !same_connection {
+            baselines.connection_id = Some(event.connection_id);
+            baselines.history.clear();
+        }
+        baselines.current = entities.clone();
+        baselines.input = replication.input;
+        baselines.history.push_back((replication.tick, entities));
+        if baselines.history.len() > HISTORY {
+            baselines.history.pop_front();
+        }
+
+        entity_map.tick = replication.tick;
+        acknowledged = Some((event.connection_id, replication.tick));
+    }
+
+    // only the latest snapshot needs acknowledging, it supersedes the others.
+    if let Some((connection_id, tick)) = acknowledged {
+        let connection = query
+            .iter()
+            .find(|connection| connection.connection_id() == connection_id);
+
+        if let Some(connection) = connection {
+            connection.send(protocol::Outgoing::Message {
+                channel: protocol::REPLICATION_CHANNEL,
+                reliability: protocol::Reliability::Unreliable,
+                payload: protocol::Payload::V1(protocol::Version1::Acknowledge(tick)),
+            });
+        }
+    }
+}
+
+impl Baselines {
+    fn get(&self, tick: u64) -> Option<&delta::Entities> {
+        self.history
+            .iter()
+            .find(|(x, _)| *x == tick)
+            .map(|(_, entities)| entities)
+    }
+}
+
+/// Brings the mirrored entities from the `previous` snapshot to the `current` one, leaving
+/// unchanged components untouched.
+fn mirror(
+    commands: &mut Commands,
+    registry: &Registry,
+    entity_map: &mut EntityMap,
+    previous: &delta::Entities,
+    current: &delta::Entities,
+) {
+    for server_entity in previous.keys().filter(|x| !current.contains_key(*x)) {
+        if let Some(entity) = entity_map.entities.remove(server_entity) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (&server_entity, components) in current {
+        let entity = *entity_map
+            .entities
+            .entry(server_entity)
+            .or_insert_with(|| commands.spawn().insert(ServerEntity(server_entity)).id());
+        let mut entity_commands = commands.entity(entity);
+        let previous = previous.get(&server_entity);
+
+        for kind in previous
+            .into_iter()
+            .flat_map(|x| x.keys())
+            .filter(|kind| !components.contains_key(*kind))
+        {
+            match registry.functions.get(kind.as_str()) {
+                Some(functions) => (functions.remove)(&mut entity_commands),
+                None => warn!(kind = %kind, "unknown component"),
+            }
+        }
+
+        for (kind, value) in components {
+            if previous.and_then(|x| x.get(kind)) == Some(value) {
+                continue;
+            }
+
+            match registry.functions.get(kind.as_str()) {
+                Some(functions) => {
+                    if let Err(error) = (functions.insert)(&mut entity_commands, value.clone()) {
+                        warn!(kind = %kind, error = %error, "component not applied");
+                    }
+                }
+                None => warn!(kind = %kind, "unknown component"),
+            }
+        }
+    }
+}
+
+fn insert<T: Replicable>(
+    entity_commands: &mut EntityCommands<'_, '_, '_>,
+    value: serde_json::Value,
+) -> serde_json::Result<()> {
+    entity_commands.insert(serde_json::from_value::<T>(value)?);
+    Ok(())
+}
+
+fn remove<T: Replicable>(entity_commands: &mut EntityCommands<'_, '_, '_>) {
+    entity_commands.remove::<T>();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{codec, conditioner, loopback::Fixture};
+
+    #[derive(Clone, Component, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct Position {
+        x: f32,
+        y: f32,
+    }
+
+    impl Replicable for Position {
+        const KIND: &'static str = "position";
+    }
+
+    #[derive(Clone, Component, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct Health(u32);
+
+    impl Replicable for Health {
+        const KIND: &'static str = "health";
+    }
+
+    fn setup(clients: usize) -> Fixture {
+        let mut fixture = Fixture::new(codec::Format::MessagePack, plugins(Plugin::server()));
+        for _ in 0..clients {
+            fixture.add_client(plugins(Plugin::client()));
+        }
+        fixture
+    }
+
+    fn plugins(plugin: Plugin) -> impl FnOnce(&mut App) {
+        |app| {
+            app.add_plugin(crate::network::Plugin::default())
+                .add_plugin(
+                    plugin
+                        .with_component::<Position>()
+                        .with_component::<Health>(),
+                );
+        }
+    }
+
+    fn mirrored<T: Component + Clone>(client: &App, entity: Entity) -> Option<T> {
+        let entity = client
+            .world
+            .resource::<EntityMap>()
+            .client_entity(entity.to_bits())?;
+        client.world.get::<T>(entity).cloned()
+    }
+
+    #[test]
+    fn test_replication() {
+        let mut setup = setup(1);
+        setup.step();
+
+        let entity = setup
+            .server
+            .world
+            .spawn()
+            .insert(Replicated)
+            .insert(Position { x: 1.0, y: 2.0 })
+            .insert(Health(10))
+            .id();
+        setup.step();
+
+        assert_eq!(
+            mirrored::<Position>(&setup.clients[0], entity),
+            Some(Position { x: 1.0, y: 2.0 })
+        );
+        assert_eq!(
+            mirrored::<Health>(&setup.clients[0], entity),
+            Some(Health(10))
+        );
+
+        // later snapshots are encoded against the acknowledged one.
+        setup.step();
+        let peers = &setup.server.world.resource::<Peers>().peers;
+        assert_eq!(peers.len(), 1);
+        assert!(peers.values().all(|peer| peer.acknowledged.is_some()));
+
+        setup.server.world.get_mut::<Position>(entity).unwrap().x = 3.0;
+        setup.server.world.entity_mut(entity).remove::<Health>();
+        setup.step();
+
+        assert_eq!(
+            mirrored::<Position>(&setup.clients[0], entity),
+            Some(Position { x: 3.0, y: 2.0 })
+        );
+        assert_eq!(mirrored::<Health>(&setup.clients[0], entity), None);
+
+        setup.server.world.despawn(entity);
+        setup.step();
+
+        assert!(setup.clients[0].world.resource::<EntityMap>().is_empty());
+        assert_eq!(
+            setup.clients[0]
+                .world
+                .query::<&ServerEntity>()
+                .iter(&setup.clients[0].world)
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_idle() {
+        let mut setup = setup(1);
+        setup
+            .server
+            .world
+            .spawn()
+            .insert(Replicated)
+            .insert(Position { x: 1.0, y: 2.0 });
+        for _ in 0..3 {
+            setup.step();
+        }
+
+        let mut reader = setup.clients[0]
+            .world
+            .resource::<Events<protocol::MessageReceivedEvent>>()
+            .get_reader();
+        let tick = setup.server.world.resource::<Snapshots>().tick;
+
+        // nothing changed since the acknowledged snapshot, so nothing is taken or sent.
+        let mut received = 0;
+        for _ in 0..3 {
+            setup.step();
+
+            let events = setup.clients[0]
+                .world
+                .resource::<Events<protocol::MessageReceivedEvent>>();
+            received += reader
+                .iter(events)
+                .filter(|event| event.channel == protocol::REPLICATION_CHANNEL)
+                .count();
+        }
+
+        let snapshots = setup.server.world.resource::<Snapshots>();
+        assert_eq!(snapshots.tick, tick);
+        assert_eq!(snapshots.history.len(), 1);
+        assert_eq!(received, 0);
+    }
+
+    #[test]
+    fn test_too_large() {
+        let mut setup = setup(1);
+        setup.step();
+
+        for x in 0..10_000 {
+            setup
+                .server
+                .world
+                .spawn()
+                .insert(Replicated)
+                .insert(Position {
+                    x: x as f32,
+                    y: 0.0,
+                });
+        }
+        setup.step();
+        setup.step();
+
+        // the client would never catch up, so the connection is closed instead.
+        assert_eq!(
+            setup
+                .server
+                .world
+                .query::<&Connection>()
+                .iter(&setup.server.world)
+                .count(),
+            0
+        );
+        assert!(setup.clients[0].world.resource::<EntityMap>().is_empty());
+    }
+
+    #[test]
+    fn test_changes_only() {
+        let mut setup = setup(1);
+        let moving = setup
+            .server
+            .world
+            .spawn()
+            .insert(Replicated)
+            .insert(Position { x: 0.0, y: 0.0 })
+            .id();
+        let still = setup
+            .server
+            .world
+            .spawn()
+            .insert(Replicated)
+            .insert(Position { x: 0.0, y: 0.0 })
+            .id();
+        setup.step();
+
+        // a local edit of the mirror survives as long as the server does not change it.
+        let mirror = setup.clients[0]
+            .world
+            .resource::<EntityMap>()
+            .client_entity(still.to_bits())
+            .unwrap();
+        setup.clients[0]
+            .world
+            .get_mut::<Position>(mirror)
+            .unwrap()
+            .x = 5.0;
+
+        setup.server.world.get_mut::<Position>(moving).unwrap().x = 1.0;
+        setup.step();
+
+        assert_eq!(
+            mirrored::<Position>(&setup.clients[0], moving).unwrap().x,
+            1.0
+        );
+        assert_eq!(
+            mirrored::<Position>(&setup.clients[0], still).unwrap().x,
+            5.0
+        );
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut setup = setup(1);
+        let entity = setup
+            .server
+            .world
+            .spawn()
+            .insert(Replicated)
+            .insert(Position { x: 1.0, y: 1.0 })
+            .id();
+        for _ in 0..3 {
+            setup.step();
+        }
+
+        // a client joining later gets the current state.
+        setup.add_client(plugins(Plugin::client()));
+        setup.step();
+        setup.step();
+
+        assert_eq!(
+            mirrored::<Position>(&setup.clients[1], entity),
+            Some(Position { x: 1.0, y: 1.0 })
+        );
+
+        // entities despawned while disconnected are gone after resuming.
+        setup.loopback.disconnect(0).unwrap();
+        setup.server.world.despawn(entity);
+        setup.step();
+        setup.loopback.connect(0).unwrap();
+        setup.step();
+        setup.step();
+
+        assert!(setup.clients[0].world.resource::<EntityMap>().is_empty());
+        assert!(setup.clients[1].world.resource::<EntityMap>().is_empty());
+    }
+
+    #[test]
+    fn test_lossy() {
+        let mut setup = setup(1);
+        let entities = (0..4)
+            .map(|x| {
+                setup
+                    .server
+                    .world
+                    .spawn()
+                    .insert(Replicated)
+                    .insert(Position {
+                        x: x as f32,
+                        y: 0.0,
+                    })
+                    .insert(Health(x))
+                    .id()
+            })
+            .collect::<Vec<_>>();
+
+        let run = |setup: &mut Fixture, conditions, steps| {
+            setup.loopback.set_conditions(Some(conditions));
+            for step in 0..steps {
+                let entity = entities[step % entities.len()];
+                setup.server.world.get_mut::<Position>(entity).unwrap().y += 1.0;
+                if step % 7 == 0 {
+                    setup.server.world.entity_mut(entity).remove::<Health>();
+                } else if step % 5 == 0 {
+                    setup
+                        .server
+                        .world
+                        .entity_mut(entity)
+                        .insert(Health(step as u32));
+                }
+
+                setup.server.update();
+                setup
+                    .loopback
+                    .advance(std::time::Duration::from_millis(10))
+                    .unwrap();
+                setup.clients[0].update();
+            }
+        };
+
+        let lossy = conditioner::Conditions {
+            latency: 30,
+            jitter: 20,
+            loss: 0.3,
+            ..conditioner::Conditions::default()
+        };
+        run(&mut setup, lossy, 200);
+
+        // an outage longer than the history leaves no acknowledged baseline.
+        let outage = conditioner::Conditions { loss: 1.0, ..lossy };
+        run(&mut setup, outage, 2 * HISTORY);
+
+        setup.loopback.set_conditions(None);
+        for _ in 0..3 {
+            setup.step();
+        }
+
+        for &entity in &entities {
+            let position = setup.server.world.get::<Position>(entity).cloned();
+            let health = setup.server.world.get::<Health>(entity).cloned();
+            assert_eq!(mirrored::<Position>(&setup.clients[0], entity), position);
+            assert_eq!(mirrored::<Health>(&setup.clients[0], entity), health);
+        }
+    }
+}
@@ -0,0 +1,488 @@
+//! Client side prediction of replicated components, reconciled with the server.
+//!
+//! A client executes its inputs on the entities it controls right away, and sends them to the
+//! server tagged with an increasing sequence. The server validates and executes them in order,
+//! and every snapshot carries the sequence of the latest input it reflects. On a snapshot the
+//! client rewinds the component to the server state, replays the inputs the server has not
+//! executed yet, and only snaps to the result when its prediction drifted further than the
+//! [`Prediction`] threshold.
+
+use bevy::{ecs::system::EntityCommands, prelude::*};
+
+use super::{Baselines, Replicable, Role, ServerEntity};
+use crate::{
+    network::{Connection, Detached},
+    protocol,
+};
+
+/// A replicated component clients change ahead of the server, registered with
+/// [`super::Plugin::with_predicted`].
+pub trait Predicted: Replicable + Clone {
+    type Input: Clone + Send + Sync + serde::Serialize + serde::de::DeserializeOwned + 'static;
+
+    /// Applies the input, the client and the server must get the same result.
+    fn execute(&mut self, input: &Self::Input);
+
+    /// Whether the server executes the input, denied inputs are undone on the client by the
+    /// next snapshot.
+    fn validate(&self, _input: &Self::Input) -> bool {
+        true
+    }
+
+    /// Distance between two states, compared to the [`Prediction`] threshold.
+    fn drift(&self, other: &Self) -> f32;
+}
+
+/// Names the connection entity allowed to send inputs for a server entity.
+#[derive(Clone, Component, Copy, Debug, PartialEq, Eq)]
+pub struct Controller(pub Entity);
+
+/// Predicts `T` on a client entity mirroring a server entity the client controls, other
+/// mirrors take the server state as it arrives.
+#[derive(Component)]
+pub struct Prediction<T: Predicted> {
+    threshold: f32,
+    /// Inputs executed locally that no snapshot reflects yet, by sequence.
+    pending: std::collections::VecDeque<(u64, T::Input)>,
+}
+
+impl<T: Predicted> Prediction<T> {
+    /// Snaps to the server state once the prediction drifts further than `threshold` from it.
+    #[must_use]
+    pub fn new(threshold: f32) -> Self {
+        Prediction {
+            threshold,
+            pending: std::collections::VecDeque::new(),
+        }
+    }
+
+    /// Inputs the server has not reflected in a snapshot yet.
+    #[must_use]
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Latest state of `T` received from the server, `T` itself may be ahead of it.
+#[derive(Clone, Component, Debug, Deref)]
+pub struct Authoritative<T>(T);
+
+/// Sent on clients to execute an input on a [`Prediction`] entity and forward it to the
+/// server.
+pub struct InputEvent<T: Predicted> {
+    pub entity: Entity,
+    pub input: T::Input,
+}
+
+/// Sequence of the latest input the server executed, by connection entity.
+#[derive(Default)]
+pub(super) struct Sequences {
+    sequences: std::collections::HashMap<Entity, u64>,
+}
+
+impl Sequences {
+    pub(super) fn get(&self, connection: Entity) -> Option<u64> {
+        self.sequences.get(&connection).copied()
+    }
+}
+
+/// Sequence of the latest input sent by the client, shared by every predicted component.
+#[derive(Default)]
+struct Sequence(u64);
+
+pub(super) fn add_systems<T: Predicted>(app: &mut App, role: Role) {
+    match role {
+        Role::Server => {
+            app.add_system(execute::<T>);
+            super::add_systems::<T>(app, role);
+        }
+        Role::Client => {
+            app.add_event::<InputEvent<T>>()
+                .init_resource::<Sequence>()
+                .add_system(predict::<T>)
+                .add_system_to_stage(CoreStage::PostUpdate, reconcile::<T>);
+        }
+    }
+}
+
+pub(super) fn insert<T: Predicted>(
+    entity_commands: &mut EntityCommands<'_, '_, '_>,
+    value: serde_json::Value,
+) -> serde_json::Result<()> {
+    entity_commands.insert(Authoritative(serde_json::from_value::<T>(value)?));
+    Ok(())
+}
+
+pub(super) fn remove<T: Predicted>(entity_commands: &mut EntityCommands<'_, '_, '_>) {
+    entity_commands.remove::<T>().remove::<Authoritative<T>>();
+}
+
+fn execute<T: Predicted>(
+    mut sequences: ResMut<Sequences>,
+    connections: Query<(Entity, &Connection)>,
+    mut query: Query<(&mut T, &Controller)>,
+    mut reader: EventReader<protocol::MessageReceivedEvent>,
+) {
+    for event in reader.iter() {
+        if event.channel != protocol::INPUT_CHANNEL {
+            continue;
+        }
+
+        let input = match &event.payload {
+            protocol::Payload::V1(protocol::Version1::Input(input)) if input.kind == T::KIND => {
+                input
+            }
+            _ => continue,
+        };
+
+        let connection = connections
+            .iter()
+            .find(|(_, connection)| connection.connection_id() == event.connection_id);
+        let connection = match connection {
+            Some((connection, _)) => connection,
+            None => continue,
+        };
+
+        let span = info_span!("connection", connection_id = event.connection_id);
+        let _guard = span.enter();
+
+        // denied inputs are acknowledged too, the snapshot carries the state without them.
+        let sequence = sequences.sequences.entry(connection).or_default();
+        *sequence = input.sequence.max(*sequence);
+
+        match query.get_mut(Entity::from_bits(input.entity)) {
+            Ok((mut component, controller)) if controller.0 == connection => {
+                match serde_json::from_value::<T::Input>(input.input.clone()) {
+                    Ok(input) if component.validate(&input) => component.execute(&input),
+                    Ok(_) => debug!(kind = T::KIND, sequence = input.sequence, "input denied"),
+                    Err(error) => warn!(kind = T::KIND, error = %error, "input not decoded"),
+                }
+            }
+            _ => warn!(
+                kind = T::KIND,
+                entity = input.entity,
+                "input for an entity not controlled"
+            ),
+        }
+    }
+
+    sequences
+        .sequences
+        .retain(|connection, _| connections.contains(*connection));
+}
+
+fn predict<T: Predicted>(
+    mut sequence: ResMut<Sequence>,
+    baselines: Res<Baselines>,
+    connections: Query<&Connection, Without<Detached>>,
+    mut query: Query<(&mut T, &mut Prediction<T>, &ServerEntity)>,
+    mut reader: EventReader<InputEvent<T>>,
+) {
+    // inputs go to the server the snapshots come from.
+    let connection = connections
+        .iter()
+        .find(|connection| Some(connection.connection_id()) == baselines.connection_id);
+
+    for event in reader.iter() {
+        let connection = match connection {
+            Some(connection) => connection,
+            None => {
+                debug!(kind = T::KIND, "input dropped while disconnected");
+                continue;
+            }
+        };
+
+        let (mut component, mut prediction, server_entity) = match query.get_mut(event.entity) {
+            Ok(predicted) => predicted,
+            Err(_) => {
+                warn!(kind = T::KIND, "input for an entity not predicted");
+                continue;
+            }
+        };
+
+        let value = match serde_json::to_value(&event.input) {
+            Ok(value) => value,
+            Err(error) => {
+                warn!(kind = T::KIND, error = %error, "input not encoded");
+                continue;
+            }
+        };
+
+        sequence.0 += 1;
+        let sent = connection.send(protocol::Outgoing::Message {
+            channel: protocol::INPUT_CHANNEL,
+            reliability: protocol::Reliability::ReliableOrdered,
+            payload: protocol::Payload::V1(protocol::Version1::Input(protocol::Input {
+                sequence: sequence.0,
+                entity: **server_entity,
+                kind: T::KIND.into(),
+                input: value,
+            })),
+        });
+        if !sent {
+            warn!(
+                connection_id = connection.connection_id(),
+                "connection closed"
+            );
+            continue;
+        }
+
+        component.execute(&event.input);
+        prediction
+            .pending
+            .push_back((sequence.0, event.input.clone()));
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn reconcile<T: Predicted>(
+    mut commands: Commands,
+    baselines: Res<Baselines>,
+    mut query: Query<(
+        Entity,
+        &Authoritative<T>,
+        ChangeTrackers<Authoritative<T>>,
+        Option<&mut T>,
+        Option<&mut Prediction<T>>,
+    )>,
+) {
+    for (entity, authoritative, tracker, component, prediction) in query.iter_mut() {
+        // a snapshot may acknowledge inputs without changing the state.
+        let acknowledged = prediction.is_some() && baselines.is_changed();
+        if !tracker.is_changed() && !acknowledged {
+            continue;
+        }
+
+        let mut state = authoritative.0.clone();
+
+        if let Some(mut prediction) = prediction {
+            // inputs the server has not applied yet, all of them while it applied none.
+            let input = baselines.input;
+            prediction
+                .pending
+                .retain(|(sequence, _)| Some(*sequence) > input);
+
+            for (_, input) in &prediction.pending {
+                state.execute(input);
+            }
+
+            let drift = component.as_ref().map(|component| component.drift(&state));
+            match drift {
+                Some(drift) if drift <= prediction.threshold => continue,
+                Some(drift) => debug!(kind = T::KIND, drift, "prediction corrected"),
+                None => {}
+            }
+        }
+
+        match component {
+            Some(mut component) => *component = state,
+            None => {
+                commands.entity(entity).insert(state);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codec, conditioner,
+        loopback::Fixture,
+        replication::{self, EntityMap},
+    };
+
+    #[derive(Clone, Component, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+    struct Position {
+        x: f32,
+    }
+
+    impl Replicable for Position {
+        const KIND: &'static str = "position";
+    }
+
+    impl Predicted for Position {
+        type Input = f32;
+
+        fn execute(&mut self, input: &f32) {
+            self.x += input;
+        }
+
+        fn validate(&self, input: &f32) -> bool {
+            input.abs() <= 1.0
+        }
+
+        fn drift(&self, other: &Position) -> f32 {
+            (self.x - other.x).abs()
+        }
+    }
+
+    struct Setup {
+        fixture: Fixture,
+        entity: Entity,
+        mirror: Entity,
+    }
+
+    impl Setup {
+        fn new() -> Setup {
+            let mut fixture = Fixture::new(
+                codec::Format::MessagePack,
+                plugins(replication::Plugin::server()),
+            );
+            fixture.add_client(plugins(replication::Plugin::client()));
+            fixture.server.update();
+
+            let server = &mut fixture.server;
+            let connection = server
+                .world
+                .query_filtered::<Entity, With<Connection>>()
+                .single(&server.world);
+            let entity = server
+                .world
+                .spawn()
+                .insert(replication::Replicated)
+                .insert(Position { x: 0.0 })
+                .insert(Controller(connection))
+                .id();
+
+            let mut setup = Setup {
+                fixture,
+                entity,
+                // replaced once the client mirrors the entity.
+                mirror: entity,
+            };
+
+            // a perfect link mirrors the entity in a couple of frames.
+            for _ in 0..3 {
+                setup.step(&[]);
+            }
+            setup.mirror = setup.fixture.clients[0]
+                .world
+                .resource::<EntityMap>()
+                .client_entity(entity.to_bits())
+                .unwrap();
+            setup.fixture.clients[0]
+                .world
+                .entity_mut(setup.mirror)
+                .insert(Prediction::<Position>::new(0.01));
+
+            setup
+                .fixture
+                .loopback
+                .set_conditions(Some(conditioner::Conditions {
+                    latency: 50,
+                    ..conditioner::Conditions::default()
+                }));
+            setup
+        }
+
+        fn step(&mut self, inputs: &[f32]) {
+            for &input in inputs {
+                self.fixture.clients[0]
+                    .world
+                    .send_event(InputEvent::<Position> {
+                        entity: self.mirror,
+                        input,
+                    });
+            }
+
+            self.fixture.advance(std::time::Duration::from_millis(10));
+        }
+
+        fn predicted(&self) -> f32 {
+            self.fixture.clients[0]
+                .world
+                .get::<Position>(self.mirror)
+                .unwrap()
+                .x
+        }
+
+        fn pending(&self) -> usize {
+            self.fixture.clients[0]
+                .world
+                .get::<Prediction<Position>>(self.mirror)
+                .unwrap()
+                .pending()
+        }
+
+        fn settle(&mut self) {
+            for _ in 0..20 {
+                self.step(&[]);
+            }
+        }
+    }
+
+    fn plugins(plugin: replication::Plugin) -> impl FnOnce(&mut App) {
+        |app| {
+            app.add_plugin(crate::network::Plugin::default())
+                .add_plugin(plugin.with_predicted::<Position>());
+        }
+    }
+
+    #[test]
+    fn test_prediction() {
+        let mut setup = Setup::new();
+
+        // inputs apply immediately and replaying them keeps snapshots from pulling back.
+        for step in 1..=20 {
+            setup.step(&[0.5]);
+            assert_eq!(setup.predicted(), step as f32 * 0.5);
+        }
+        assert!(setup.pending() > 0);
+
+        setup.settle();
+
+        assert_eq!(setup.pending(), 0);
+        assert_eq!(
+            setup.fixture.server.world.get::<Position>(setup.entity),
+            Some(&Position { x: 10.0 })
+        );
+        assert_eq!(setup.predicted(), 10.0);
+    }
+
+    #[test]
+    fn test_reconciliation() {
+        let mut setup = Setup::new();
+
+        // the server denies the input, the client snaps back once it learns.
+        setup.step(&[5.0]);
+        assert_eq!(setup.predicted(), 5.0);
+        setup.settle();
+
+        assert_eq!(setup.pending(), 0);
+        assert_eq!(setup.predicted(), 0.0);
+
+        // a server change within the threshold keeps the prediction.
+        setup
+            .fixture
+            .server
+            .world
+            .get_mut::<Position>(setup.entity)
+            .unwrap()
+            .x = 0.005;
+        setup.settle();
+
+        assert_eq!(setup.predicted(), 0.0);
+        assert_eq!(
+            setup.fixture.clients[0]
+                .world
+                .get::<Authoritative<Position>>(setup.mirror)
+                .unwrap()
+                .x,
+            0.005
+        );
+
+        // beyond it, the client takes the server state and replays what is pending.
+        setup
+            .fixture
+            .server
+            .world
+            .get_mut::<Position>(setup.entity)
+            .unwrap()
+            .x = 3.0;
+        setup.step(&[1.0]);
+        setup.settle();
+
+        assert_eq!(setup.predicted(), 4.0);
+    }
+}
@@ -0,0 +1,55 @@
+use bevy::{app::AppExit, prelude::*};
+
+/// Progress of the shutdown sequence, broadcast to the tasks taking part in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    Running,
+    /// Readiness fails and the QUIC server closes its connections.
+    Draining,
+    /// The grace period elapsed, the Bevy app exits.
+    Stopped,
+}
+
+/// Waits for a termination signal, then drains the server for the grace period.
+pub(crate) async fn run(
+    grace_period: std::time::Duration,
+    health: crate::health::Health,
+    sender: tokio::sync::watch::Sender<Phase>,
+) -> crate::Result<()> {
+    signal().await?;
+
+    info!(grace_period = ?grace_period, "draining");
+    health.set_draining(true);
+    sender.send(Phase::Draining)?;
+
+    tokio::time::sleep(grace_period).await;
+
+    info!("stopping");
+    sender.send(Phase::Stopped)?;
+
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn signal() -> crate::Result<()> {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+
+    tokio::select! {
+        _ = terminate.recv() => Ok(()),
+        result = tokio::signal::ctrl_c() => Ok(result?),
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() -> crate::Result<()> {
+    Ok(tokio::signal::ctrl_c().await?)
+}
+
+pub(crate) fn exit(
+    receiver: Res<tokio::sync::watch::Receiver<Phase>>,
+    mut writer: EventWriter<AppExit>,
+) {
+    if *receiver.borrow() == Phase::Stopped {
+        writer.send(AppExit);
+    }
+}
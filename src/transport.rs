@@ -0,0 +1,282 @@
+//! Half of a connection every transport shares, the QUIC transport and [`crate::loopback`] only
+//! differ in how instructions travel.
+//!
+//! A transport carries the [`protocol::Outgoing`] instructions of the network plugin to the peer
+//! through its [`Pipe`], and hands whatever arrives to the [`Session`] of the receiving side,
+//! which turns it into the [`protocol::Event`]s the network plugin reads. Pings, responses and
+//! lost connections are therefore reported the same way whatever the transport.
+
+use bevy::prelude::*;
+
+use crate::protocol;
+
+pub(crate) type Response = Result<protocol::Payload, protocol::RpcError>;
+
+type Responder = tokio::sync::oneshot::Sender<Response>;
+
+/// Carries instructions of the network plugin to the peer.
+pub(crate) trait Pipe {
+    /// Carries a message, request or close. Responses never reach the pipe, the [`Session`]
+    /// hands them to the request they answer, see [`unanswerable`].
+    fn carry(&mut self, outgoing: protocol::Outgoing) -> crate::Result<()>;
+}
+
+impl Pipe for tokio::sync::mpsc::UnboundedSender<protocol::Outgoing> {
+    fn carry(&mut self, outgoing: protocol::Outgoing) -> crate::Result<()> {
+        self.send(outgoing).map_err(Into::into)
+    }
+}
+
+/// Error of a pipe handed a response, which has no request left to travel with.
+pub(crate) fn unanswerable(request_id: u64) -> crate::Error {
+    crate::Error::Protocol(format!(
+        "response to request {request_id} bypassed its session"
+    ))
+}
+
+/// How a request of the peer is answered.
+pub(crate) enum Answer {
+    /// By the transport itself, like keep-alive pings.
+    Now(protocol::Payload),
+    /// By the network plugin, through [`Session::dispatch`].
+    Later {
+        request_id: u64,
+        receiver: tokio::sync::oneshot::Receiver<Response>,
+    },
+}
+
+/// One side of a connection, as its network plugin sees it.
+#[derive(Clone)]
+pub(crate) struct Session {
+    connection_id: usize,
+    sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    pending: PendingRequests,
+}
+
+impl Session {
+    pub(crate) fn new(
+        connection_id: usize,
+        sender: tokio::sync::mpsc::UnboundedSender<protocol::Event>,
+    ) -> Session {
+        Session {
+            connection_id,
+            sender,
+            pending: PendingRequests::default(),
+        }
+    }
+
+    pub(crate) fn connection_id(&self) -> usize {
+        self.connection_id
+    }
+
+    /// Hands an instruction of the network plugin to the pipe, or a response to the request it
+    /// answers.
+    pub(crate) fn dispatch(
+        &self,
+        pipe: &mut impl Pipe,
+        outgoing: protocol::Outgoing,
+    ) -> crate::Result<()> {
+        match outgoing {
+            protocol::Outgoing::Response {
+                request_id,
+                response,
+            } => {
+                self.pending.respond(request_id, response);
+                Ok(())
+            }
+            outgoing => pipe.carry(outgoing),
+        }
+    }
+
+    /// A message of the peer arrived on `channel`.
+    pub(crate) fn message(&self, channel: u8, payload: protocol::Payload) -> crate::Result<()> {
+        self.send(protocol::Event::MessageReceived(
+            protocol::MessageReceivedEvent {
+                connection_id: self.connection_id,
+                channel,
+                payload,
+            },
+        ))
+    }
+
+    /// A request of the peer arrived, pings are answered right away.
+    pub(crate) fn request(&self, payload: protocol::Payload) -> crate::Result<Answer> {
+        if payload == protocol::Payload::V1(protocol::Version1::Ping) {
+            self.send(protocol::Event::PayloadReceived(
+                protocol::PayloadReceivedEvent {
+                    connection_id: self.connection_id,
+                    payload,
+                },
+            ))?;
+
+            return Ok(Answer::Now(protocol::Payload::V1(protocol::Version1::Pong)));
+        }
+
+        let (request_id, receiver) = self.pending.insert();
+
+        self.send(protocol::Event::RequestReceived(
+            protocol::RequestReceivedEvent {
+                connection_id: self.connection_id,
+                request_id,
+                payload,
+            },
+        ))?;
+
+        Ok(Answer::Later {
+            request_id,
+            receiver,
+        })
+    }
+
+    /// Stops waiting for the network plugin to answer a request of the peer.
+    pub(crate) fn forget(&self, request_id: u64) {
+        self.pending.remove(request_id);
+    }
+
+    /// The peer answered a request of the network plugin.
+    pub(crate) fn response(&self, request_id: u64, response: Response) -> crate::Result<()> {
+        self.send(protocol::Event::ResponseReceived(
+            protocol::ResponseReceivedEvent {
+                connection_id: self.connection_id,
+                request_id,
+                response,
+            },
+        ))
+    }
+
+    /// The connection is gone, requests of the peer the network plugin did not answer yet are
+    /// dropped with it.
+    pub(crate) fn destroyed(&self) -> crate::Result<()> {
+        self.pending.clear();
+
+        self.send(protocol::Event::ConnectionDestroyed(
+            protocol::ConnectionDestroyedEvent {
+                connection_id: self.connection_id,
+            },
+        ))
+    }
+
+    fn send(&self, event: protocol::Event) -> crate::Result<()> {
+        self.sender.send(event).map_err(Into::into)
+    }
+}
+
+/// Requests received from the peer that are waiting for a response.
+#[derive(Clone, Default)]
+struct PendingRequests {
+    next_request_id: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    responders: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<u64, Responder>>>,
+}
+
+impl PendingRequests {
+    fn insert(&self) -> (u64, tokio::sync::oneshot::Receiver<Response>) {
+        let request_id = self
+            .next_request_id
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+
+        self.responders
+            .lock()
+            .expect("poisoned")
+            .insert(request_id, sender);
+
+        (request_id, receiver)
+    }
+
+    fn remove(&self, request_id: u64) -> Option<Responder> {
+        self.responders
+            .lock()
+            .expect("poisoned")
+            .remove(&request_id)
+    }
+
+    fn clear(&self) {
+        self.responders.lock().expect("poisoned").clear();
+    }
+
+    /// Hands the response to whoever waits for it, which sends it back on its own.
+    fn respond(&self, request_id: u64, response: Response) {
+        match self.remove(request_id) {
+            Some(responder) => {
+                let _ = responder.send(response);
+            }
+            None => warn!(request_id = request_id, "response to unknown request"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session() {
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (mut pipe, mut carried) = tokio::sync::mpsc::unbounded_channel();
+        let session = Session::new(1, sender);
+
+        match session
+            .request(protocol::Payload::V1(protocol::Version1::Ping))
+            .unwrap()
+        {
+            Answer::Now(response) => {
+                assert_eq!(response, protocol::Payload::V1(protocol::Version1::Pong));
+            }
+            Answer::Later { .. } => panic!("ping left to the network plugin"),
+        }
+        assert!(matches!(
+            receiver.try_recv().unwrap(),
+            protocol::Event::PayloadReceived(_)
+        ));
+
+        let (answered, mut answered_receiver) = match session
+            .request(protocol::Payload::V1(protocol::Version1::Pong))
+            .unwrap()
+        {
+            Answer::Later {
+                request_id,
+                receiver,
+            } => (request_id, receiver),
+            Answer::Now(_) => panic!("request answered by the transport"),
+        };
+        let mut dropped = match session
+            .request(protocol::Payload::V1(protocol::Version1::Pong))
+            .unwrap()
+        {
+            Answer::Later { receiver, .. } => receiver,
+            Answer::Now(_) => panic!("request answered by the transport"),
+        };
+
+        // responses are resolved locally, everything else travels through the pipe.
+        session
+            .dispatch(
+                &mut pipe,
+                protocol::Outgoing::Response {
+                    request_id: answered,
+                    response: Err(protocol::RpcError::Unhandled),
+                },
+            )
+            .unwrap();
+        session
+            .dispatch(
+                &mut pipe,
+                protocol::Outgoing::Close {
+                    code: protocol::CloseReason::Kicked,
+                    reason: "done".into(),
+                },
+            )
+            .unwrap();
+        session.destroyed().unwrap();
+
+        assert_eq!(
+            answered_receiver.try_recv().unwrap(),
+            Err(protocol::RpcError::Unhandled)
+        );
+        assert!(dropped.try_recv().is_err());
+        assert!(matches!(
+            carried.try_recv().unwrap(),
+            protocol::Outgoing::Close { .. }
+        ));
+        assert!(carried.try_recv().is_err());
+    }
+}